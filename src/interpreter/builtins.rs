@@ -0,0 +1,442 @@
+use std::cell::RefCell;
+use crate::interpreter::context::Value;
+use crate::interpreter::error::RuntimeError;
+use crate::interpreter::range::IntRange;
+use crate::interpreter::thread::{channel, ChannelEnd};
+use std::time::Duration;
+use num_bigint::BigInt;
+use num_traits::{Signed, ToPrimitive};
+use unicode_segmentation::UnicodeSegmentation;
+
+// 組み込み関数の名前一覧
+const FUNCTIONS: &[&str] = &[
+    "len", "str", "int", "bigint", "char", "error", "implements",
+    "Ok", "Err", "Some", "unwrap", "unwrap_or", "is_ok", "is_err", "is_some", "is_none",
+    "chan", "send", "recv", "close", "join",
+    "wrapping_add", "wrapping_sub", "wrapping_mul", "wrapping_div", "divmod",
+];
+
+// 関数値の呼び出しやタスクの操作が必要な組み込み関数(呼び出しは評価器が行う)
+pub const EVALUATOR_FUNCTIONS: &[&str] = &["map_err", "next", "spawn", "sleep", "now", "thread_spawn"];
+
+pub fn exists(name: &str) -> bool {
+    FUNCTIONS.contains(&name) || EVALUATOR_FUNCTIONS.contains(&name)
+}
+
+// 組み込みの定数
+pub fn constant(name: &str) -> Option<Value> {
+    match name {
+        "None" => Some(Value::None),
+        _ => None,
+    }
+}
+
+// 組み込み関数を呼び出す
+// 該当する組み込み関数がなければNoneを返す
+pub fn call(name: &str, args: Vec<Value>) -> Option<Result<Value, RuntimeError>> {
+    let result = match name {
+        "len" => len(args),
+        "str" => str(args),
+        "int" => int(args),
+        "bigint" => bigint(args),
+        "char" => char(args),
+        "error" => error(args),
+        "implements" => implements(args),
+        "Ok" => wrap("Ok", args, Value::Ok),
+        "Err" => wrap("Err", args, Value::Err),
+        "Some" => wrap("Some", args, Value::Some),
+        "unwrap" => unwrap(args),
+        "unwrap_or" => unwrap_or(args),
+        "is_ok" => check_variant("is_ok", args, |v| matches!(v, Value::Ok(_))),
+        "is_err" => check_variant("is_err", args, |v| matches!(v, Value::Err(_))),
+        "is_some" => check_variant("is_some", args, |v| matches!(v, Value::Some(_))),
+        "is_none" => check_variant("is_none", args, |v| matches!(v, Value::None)),
+        "chan" => chan(args),
+        "send" => send(args),
+        "recv" => recv(args),
+        "close" => close(args),
+        "join" => join(args),
+        "wrapping_add" => wrapping("wrapping_add", args, i64::wrapping_add),
+        "wrapping_sub" => wrapping("wrapping_sub", args, i64::wrapping_sub),
+        "wrapping_mul" => wrapping("wrapping_mul", args, i64::wrapping_mul),
+        "wrapping_div" => wrapping("wrapping_div", args, i64::wrapping_div),
+        "divmod" => divmod(args),
+        _ => return None,
+    };
+    Some(result)
+}
+
+// 組み込みメソッドを呼び出す
+// レシーバの型にそのメソッドがなければNoneを返す(呼び出し側で自由関数にフォールバックする)
+pub fn call_method(receiver: &Value, method: &str, args: &[Value]) -> Option<Result<Value, RuntimeError>> {
+    match receiver {
+        Value::String(s) => string_method(s, method, args),
+        Value::Char(c) => char_method(*c, method, args),
+        Value::Int(i) => int_method(*i, method, args),
+        Value::BigInt(i) => big_int_method(i, method, args),
+        Value::List(items) => list_method(items, method, args),
+        Value::Range(range) => range_method(range, method, args),
+        Value::Error { kind, message } => error_method(kind, message, method, args),
+        _ => None,
+    }
+}
+
+fn type_error(message: String) -> RuntimeError {
+    RuntimeError::new("TypeError", message)
+}
+
+fn expect_args(name: &str, args: &[Value], count: usize) -> Result<(), RuntimeError> {
+    if args.len() != count {
+        return Err(type_error(format!("Built-in function '{}' expects {} arguments, got {}", name, count, args.len())));
+    }
+    Ok(())
+}
+
+fn expect_string<'a>(name: &str, value: &'a Value) -> Result<&'a str, RuntimeError> {
+    match value {
+        Value::String(s) => Ok(s),
+        other => Err(type_error(format!("'{}' expects a string argument, got {}", name, other.type_name()))),
+    }
+}
+
+fn expect_int(name: &str, value: &Value) -> Result<i64, RuntimeError> {
+    match value {
+        Value::Int(i) => Ok(*i),
+        other => Err(type_error(format!("'{}' expects an int argument, got {}", name, other.type_name()))),
+    }
+}
+
+fn overflow(expression: String) -> RuntimeError {
+    RuntimeError::new("OverflowError", format!("Integer overflow: {}", expression))
+}
+
+fn zero_division(expression: String) -> RuntimeError {
+    RuntimeError::new("ZeroDivisionError", format!("Division by zero: {}", expression))
+}
+
+fn len(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("len", &args, 1)?;
+    match &args[0] {
+        Value::List(items) => Ok(Value::Int(items.borrow().len() as i64)),
+        Value::String(s) => Ok(Value::Int(s.chars().count() as i64)),
+        Value::Range(range) => i64::try_from(range.len()).map(Value::Int).map_err(|_| overflow(format!("len({})", range))),
+        other => Err(type_error(format!("len() is not supported for {}", other.type_name()))),
+    }
+}
+
+fn str(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("str", &args, 1)?;
+    Ok(Value::String(args[0].to_string()))
+}
+
+// 文字列を整数に変換する(変換できなければValueError)
+// bigintはintに収まるときだけ変換できる
+fn int(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("int", &args, 1)?;
+    match &args[0] {
+        Value::Int(i) => Ok(Value::Int(*i)),
+        Value::BigInt(i) => i
+            .to_i64()
+            .map(Value::Int)
+            .ok_or_else(|| RuntimeError::new("ValueError", format!("{} is too large for int", i))),
+        Value::String(s) => s
+            .trim()
+            .parse::<i64>()
+            .map(Value::Int)
+            .map_err(|_| RuntimeError::new("ValueError", format!("Cannot convert '{}' to int", s))),
+        other => Err(type_error(format!("int() is not supported for {}", other.type_name()))),
+    }
+}
+
+// intや文字列を多倍長整数に変換する
+fn bigint(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("bigint", &args, 1)?;
+    match &args[0] {
+        Value::Int(i) => Ok(Value::BigInt(BigInt::from(*i))),
+        Value::BigInt(i) => Ok(Value::BigInt(i.clone())),
+        Value::String(s) => s
+            .trim()
+            .parse::<BigInt>()
+            .map(Value::BigInt)
+            .map_err(|_| RuntimeError::new("ValueError", format!("Cannot convert '{}' to bigint", s))),
+        other => Err(type_error(format!("bigint() is not supported for {}", other.type_name()))),
+    }
+}
+
+// コードポイントの番号から文字を作る(サロゲートや範囲外の番号はValueError)
+fn char(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("char", &args, 1)?;
+    let code = expect_int("char", &args[0])?;
+    u32::try_from(code)
+        .ok()
+        .and_then(char::from_u32)
+        .map(Value::Char)
+        .ok_or_else(|| RuntimeError::new("ValueError", format!("{} is not a valid code point", code)))
+}
+
+// エラー値を作る: error(message) / error(kind, message)
+fn error(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (kind, message) = match args.as_slice() {
+        [message] => ("Error", expect_string("error", message)?),
+        [kind, message] => (expect_string("error", kind)?, expect_string("error", message)?),
+        _ => return Err(type_error(format!("Built-in function 'error' expects 1 or 2 arguments, got {}", args.len()))),
+    };
+    Ok(Value::Error {
+        kind: kind.to_string(),
+        message: message.to_string(),
+    })
+}
+
+// implements(value, Trait): クラスかインスタンスがトレイトを実装しているか
+fn implements(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("implements", &args, 2)?;
+    let target = match &args[1] {
+        Value::Trait(target) => target,
+        other => return Err(type_error(format!("'implements' expects a trait, got {}", other.type_name()))),
+    };
+    let result = match &args[0] {
+        Value::Instance(instance) => instance.borrow().class.implements(target),
+        Value::Class(class) => class.implements(target),
+        _ => false,
+    };
+    Ok(Value::Int(result as i64))
+}
+
+fn wrap(name: &str, mut args: Vec<Value>, variant: fn(Box<Value>) -> Value) -> Result<Value, RuntimeError> {
+    expect_args(name, &args, 1)?;
+    Ok(variant(Box::new(args.remove(0))))
+}
+
+// ResultかOptionであることを確かめる
+fn expect_wrapper<'a>(name: &str, value: &'a Value) -> Result<&'a Value, RuntimeError> {
+    match value {
+        Value::Ok(_) | Value::Err(_) | Value::Some(_) | Value::None => Ok(value),
+        other => Err(type_error(format!("'{}' expects a result or an option, got {}", name, other.type_name()))),
+    }
+}
+
+fn unwrap(mut args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("unwrap", &args, 1)?;
+    expect_wrapper("unwrap", &args[0])?;
+    match args.remove(0) {
+        Value::Ok(value) | Value::Some(value) => Ok(*value),
+        failure => Err(RuntimeError::new("ValueError", format!("Called unwrap on {}", failure))),
+    }
+}
+
+fn unwrap_or(mut args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("unwrap_or", &args, 2)?;
+    expect_wrapper("unwrap_or", &args[0])?;
+    let default = args.pop().unwrap();
+    match args.remove(0) {
+        Value::Ok(value) | Value::Some(value) => Ok(*value),
+        _ => Ok(default),
+    }
+}
+
+fn check_variant(name: &str, args: Vec<Value>, predicate: fn(&Value) -> bool) -> Result<Value, RuntimeError> {
+    expect_args(name, &args, 1)?;
+    let value = expect_wrapper(name, &args[0])?;
+    Ok(Value::Int(predicate(value) as i64))
+}
+
+// wrapping_add(a, b) など: 桁あふれしたら折り返す(0での割り算だけはエラー)
+fn wrapping(name: &str, args: Vec<Value>, op: fn(i64, i64) -> i64) -> Result<Value, RuntimeError> {
+    expect_args(name, &args, 2)?;
+    let (l, r) = (expect_int(name, &args[0])?, expect_int(name, &args[1])?);
+    if name == "wrapping_div" && r == 0 {
+        return Err(zero_division(format!("{}({}, {})", name, l, r)));
+    }
+    Ok(Value::Int(op(l, r)))
+}
+
+// divmod(a, b): 商と余りのリスト(/ と % と同じく0に向かって切り捨てる)
+fn divmod(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("divmod", &args, 2)?;
+    let (l, r) = (expect_int("divmod", &args[0])?, expect_int("divmod", &args[1])?);
+    if r == 0 {
+        return Err(zero_division(format!("divmod({}, {})", l, r)));
+    }
+    match (l.checked_div(r), l.checked_rem(r)) {
+        (Some(quotient), Some(remainder)) => Ok(Value::list(vec![Value::Int(quotient), Value::Int(remainder)])),
+        _ => Err(overflow(format!("divmod({}, {})", l, r))),
+    }
+}
+
+// chan() / chan("int"): スレッド間で値を受け渡すチャンネル(型名を渡すとその型の値だけ送れる)
+fn chan(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match args.as_slice() {
+        [] => Ok(channel(None)),
+        [element] => Ok(channel(Some(expect_string("chan", element)?.to_string()))),
+        _ => Err(type_error(format!("Built-in function 'chan' expects 0 or 1 arguments, got {}", args.len()))),
+    }
+}
+
+fn expect_channel<'a>(name: &str, value: &'a Value) -> Result<&'a ChannelEnd, RuntimeError> {
+    match value {
+        Value::Channel(end) => Ok(end),
+        other => Err(type_error(format!("'{}' expects a channel, got {}", name, other.type_name()))),
+    }
+}
+
+fn send(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("send", &args, 2)?;
+    expect_channel("send", &args[0])?.channel.send(&args[1])?;
+    Ok(Value::Unit)
+}
+
+// recv(ch) / recv(ch, timeout_ms): 届いた値をSomeで返し、もう届かなければNoneを返す
+fn recv(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (end, timeout) = match args.as_slice() {
+        [channel] => (expect_channel("recv", channel)?, None),
+        [channel, ms] => match expect_int("recv", ms)? {
+            ms if ms >= 0 => (expect_channel("recv", channel)?, Some(Duration::from_millis(ms as u64))),
+            ms => return Err(RuntimeError::new("ValueError", format!("'recv' expects a non-negative timeout, got {}", ms))),
+        },
+        _ => return Err(type_error(format!("Built-in function 'recv' expects 1 or 2 arguments, got {}", args.len()))),
+    };
+    Ok(end.recv(timeout).map_or(Value::None, |value| Value::Some(Box::new(value))))
+}
+
+fn close(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("close", &args, 1)?;
+    expect_channel("close", &args[0])?.channel.close();
+    Ok(Value::Unit)
+}
+
+// join(thread): スレッドが終わるのを待って、関数の戻り値を返す
+fn join(args: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args("join", &args, 1)?;
+    match &args[0] {
+        Value::Thread(thread) => thread.borrow_mut().join(),
+        other => Err(type_error(format!("'join' expects a thread, got {}", other.type_name()))),
+    }
+}
+
+// 文字列の長さや分割は、単位ごとに別のメソッドにする
+// len/chars はコードポイント、byte_len/bytes はUTF-8のバイト、graphemes は見た目の一文字(書記素クラスタ)
+fn string_method(s: &str, method: &str, args: &[Value]) -> Option<Result<Value, RuntimeError>> {
+    let result = match method {
+        "len" => expect_args(method, args, 0).map(|_| Value::Int(s.chars().count() as i64)),
+        "chars" => expect_args(method, args, 0).map(|_| Value::list(s.chars().map(Value::Char).collect())),
+        "byte_len" => expect_args(method, args, 0).map(|_| Value::Int(s.len() as i64)),
+        "bytes" => expect_args(method, args, 0).map(|_| Value::list(s.bytes().map(|b| Value::Int(b as i64)).collect())),
+        "graphemes" => expect_args(method, args, 0)
+            .map(|_| Value::list(s.graphemes(true).map(|g| Value::String(g.to_string())).collect())),
+        "upper" => expect_args(method, args, 0).map(|_| Value::String(s.to_uppercase())),
+        "lower" => expect_args(method, args, 0).map(|_| Value::String(s.to_lowercase())),
+        "trim" => expect_args(method, args, 0).map(|_| Value::String(s.trim().to_string())),
+        "contains" => expect_args(method, args, 1)
+            .and_then(|_| expect_string(method, &args[0]))
+            .map(|pat| Value::Int(s.contains(pat) as i64)),
+        "starts_with" => expect_args(method, args, 1)
+            .and_then(|_| expect_string(method, &args[0]))
+            .map(|pat| Value::Int(s.starts_with(pat) as i64)),
+        "ends_with" => expect_args(method, args, 1)
+            .and_then(|_| expect_string(method, &args[0]))
+            .map(|pat| Value::Int(s.ends_with(pat) as i64)),
+        "split" => expect_args(method, args, 1)
+            .and_then(|_| expect_string(method, &args[0]))
+            .map(|sep| Value::list(s.split(sep).map(|part| Value::String(part.to_string())).collect())),
+        "replace" => expect_args(method, args, 2)
+            .and_then(|_| Ok((expect_string(method, &args[0])?, expect_string(method, &args[1])?)))
+            .map(|(from, to)| Value::String(s.replace(from, to))),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn char_method(c: char, method: &str, args: &[Value]) -> Option<Result<Value, RuntimeError>> {
+    let result = match method {
+        "code" => expect_args(method, args, 0).map(|_| Value::Int(c as i64)),
+        "byte_len" => expect_args(method, args, 0).map(|_| Value::Int(c.len_utf8() as i64)),
+        "is_alphabetic" => expect_args(method, args, 0).map(|_| Value::Int(c.is_alphabetic() as i64)),
+        "is_numeric" => expect_args(method, args, 0).map(|_| Value::Int(c.is_numeric() as i64)),
+        "is_whitespace" => expect_args(method, args, 0).map(|_| Value::Int(c.is_whitespace() as i64)),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn int_method(i: i64, method: &str, args: &[Value]) -> Option<Result<Value, RuntimeError>> {
+    let result = match method {
+        "abs" => expect_args(method, args, 0).and_then(|_| {
+            i.checked_abs().map(Value::Int).ok_or_else(|| overflow(format!("{}.abs()", i)))
+        }),
+        "pow" => expect_args(method, args, 1)
+            .and_then(|_| expect_int(method, &args[0]))
+            .and_then(|exp| {
+                u32::try_from(exp)
+                    .map_err(|_| RuntimeError::new("ValueError", format!("'pow' expects a non-negative exponent, got {}", exp)))
+            })
+            .and_then(|exp| i.checked_pow(exp).map(Value::Int).ok_or_else(|| overflow(format!("{}.pow({})", i, exp)))),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn big_int_method(i: &BigInt, method: &str, args: &[Value]) -> Option<Result<Value, RuntimeError>> {
+    let result = match method {
+        "abs" => expect_args(method, args, 0).map(|_| Value::BigInt(i.abs())),
+        "pow" => expect_args(method, args, 1)
+            .and_then(|_| expect_int(method, &args[0]))
+            .and_then(|exp| {
+                u32::try_from(exp)
+                    .map_err(|_| RuntimeError::new("ValueError", format!("'pow' expects a non-negative exponent, got {}", exp)))
+            })
+            .map(|exp| Value::BigInt(i.pow(exp))),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn list_method(items: &RefCell<Vec<Value>>, method: &str, args: &[Value]) -> Option<Result<Value, RuntimeError>> {
+    let result = match method {
+        "len" => expect_args(method, args, 0).map(|_| Value::Int(items.borrow().len() as i64)),
+        "push" => expect_args(method, args, 1).map(|_| {
+            items.borrow_mut().push(args[0].clone());
+            Value::Unit
+        }),
+        "pop" => expect_args(method, args, 0).and_then(|_| {
+            items.borrow_mut().pop().ok_or_else(|| RuntimeError::new("IndexError", "pop() on an empty list"))
+        }),
+        "contains" => expect_args(method, args, 1).map(|_| Value::Int(items.borrow().contains(&args[0]) as i64)),
+        "join" => expect_args(method, args, 1)
+            .and_then(|_| expect_string(method, &args[0]))
+            .map(|sep| {
+                let parts: Vec<String> = items.borrow().iter().map(|item| item.to_string()).collect();
+                Value::String(parts.join(sep))
+            }),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn range_method(range: &IntRange, method: &str, args: &[Value]) -> Option<Result<Value, RuntimeError>> {
+    let result = match method {
+        "len" => expect_args(method, args, 0).and_then(|_| {
+            i64::try_from(range.len()).map(Value::Int).map_err(|_| overflow(format!("({}).len()", range)))
+        }),
+        "step" => expect_args(method, args, 1)
+            .and_then(|_| expect_int(method, &args[0]))
+            .and_then(|step| range.with_step(step).map_err(|e| RuntimeError::new("ValueError", e)))
+            .map(Value::Range),
+        "contains" => expect_args(method, args, 1)
+            .and_then(|_| expect_int(method, &args[0]))
+            .map(|i| Value::Int(range.contains(i) as i64)),
+        // 明示的に呼んだときだけリストを作る
+        "to_list" => expect_args(method, args, 0)
+            .map(|_| Value::list(range.iter().map(Value::Int).collect())),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn error_method(kind: &str, message: &str, method: &str, args: &[Value]) -> Option<Result<Value, RuntimeError>> {
+    let result = match method {
+        "kind" => expect_args(method, args, 0).map(|_| Value::String(kind.to_string())),
+        "message" => expect_args(method, args, 0).map(|_| Value::String(message.to_string())),
+        _ => return None,
+    };
+    Some(result)
+}
@@ -1,10 +1,119 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use crate::interpreter::generator::Generator;
+use crate::interpreter::module::Module;
+use crate::interpreter::scheduler::AsyncTask;
+use crate::interpreter::range::IntRange;
+use crate::interpreter::thread::{ChannelEnd, ThreadHandle};
 use crate::parser::ast::*;
+use num_bigint::BigInt;
 
+// 実行時の値
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Int(i64),
+    // 多倍長整数(intとの演算の結果もbigintになる)
+    BigInt(BigInt),
     String(String),
-    Function(Vec<String>, Box<Expr>),
+    // 一つのコードポイント
+    Char(char),
+    // リストは参照で共有される
+    List(Rc<RefCell<Vec<Value>>>),
+    Range(IntRange),
+    Function(Rc<Function>),
+    // 組み込み関数(名前で呼び出す)
+    Builtin(String),
+    // catchで受け取る組み込みのエラー
+    Error {
+        kind: String,
+        message: String,
+    },
+    // 値としてのエラー処理に使うResult/Option
+    Ok(Box<Value>),
+    Err(Box<Value>),
+    Some(Box<Value>),
+    None,
+    Class(Rc<Class>),
+    Trait(Rc<Trait>),
+    // インスタンスは参照で共有され、フィールドは書き換えられる
+    Instance(Rc<RefCell<Instance>>),
+    Generator(Rc<RefCell<Generator>>),
+    Task(Rc<RefCell<AsyncTask>>),
+    Channel(Rc<ChannelEnd>),
+    Thread(Rc<RefCell<ThreadHandle>>),
+    Module(Rc<Module>),
+    Unit,
+}
+
+// type_nameが返す型名の一覧
+pub const TYPE_NAMES: &[&str] = &[
+    "int", "bigint", "string", "char", "list", "range", "function", "error", "result", "option",
+    "class", "trait", "instance", "generator", "task", "channel", "thread", "module", "unit",
+];
+
+// ユーザー定義関数
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Param>,
+    // 戻り値の型の注釈(普通の関数の戻り値だけを検査する)
+    pub returns: Option<String>,
+    pub body: Expr,
+    // ジェネレーターとasync関数は、呼び出すと本体を実行せずにジェネレーターやタスクを返す
+    pub kind: FunctionKind,
+    // 定義したモジュール(本体はそのモジュールのグローバルスコープで実行する)
+    pub module: usize,
+}
+
+// クラス(親クラスは単一継承)
+// implでメソッドとトレイトが後から追加される
+#[derive(Debug, PartialEq)]
+pub struct Class {
+    pub name: String,
+    pub parent: Option<Rc<Class>>,
+    pub methods: RefCell<HashMap<String, Rc<Function>>>,
+    pub traits: RefCell<Vec<Rc<Trait>>>,
+}
+
+impl Class {
+    // メソッドを親クラスまでたどって探し、定義しているクラスと一緒に返す
+    pub fn find_method(self: &Rc<Self>, name: &str) -> Option<(Rc<Function>, Rc<Class>)> {
+        let mut class = Some(self);
+        while let Some(current) = class {
+            if let Some(method) = current.methods.borrow().get(name) {
+                return Some((method.clone(), current.clone()));
+            }
+            class = current.parent.as_ref();
+        }
+        None
+    }
+
+    // 自分自身か親クラスのどれかがclassか
+    pub fn is_subclass_of(&self, class: &Rc<Class>) -> bool {
+        std::ptr::eq(self, Rc::as_ptr(class)) || self.parent.as_ref().is_some_and(|parent| parent.is_subclass_of(class))
+    }
+
+    // 親クラスが実装したトレイトも含めて調べる
+    pub fn implements(&self, target: &Rc<Trait>) -> bool {
+        self.traits.borrow().iter().any(|t| Rc::ptr_eq(t, target))
+            || self.parent.as_ref().is_some_and(|parent| parent.implements(target))
+    }
+}
+
+// トレイト: 実装が必要なメソッドの名前と、既定の実装を持つメソッド
+#[derive(Debug, PartialEq)]
+pub struct Trait {
+    pub name: String,
+    pub required: Vec<String>,
+    pub defaults: HashMap<String, Rc<Function>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: BTreeMap<String, Value>,
 }
 
 impl Value {
@@ -12,56 +121,332 @@ impl Value {
     pub fn from_literal(literal: Literal) -> Result<Self, String> {
         match literal {
             Literal::Int(value) => Ok(Value::Int(value)),
+            Literal::BigInt(value) => Ok(Value::BigInt(value)),
             Literal::String(value) => Ok(Value::String(value)),
-            _ => Err("Unsupported literal type for conversion".into()),
+            Literal::Char(value) => Ok(Value::Char(value)),
+            Literal::Unit => Ok(Value::Unit),
+        }
+    }
+
+    pub fn list(items: Vec<Value>) -> Self {
+        Value::List(Rc::new(RefCell::new(items)))
+    }
+
+    // エラーメッセージや型の注釈に使う型名
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::BigInt(_) => "bigint",
+            Value::String(_) => "string",
+            Value::Char(_) => "char",
+            Value::List(_) => "list",
+            Value::Range(_) => "range",
+            Value::Function(_) | Value::Builtin(_) => "function",
+            Value::Error { .. } => "error",
+            Value::Ok(_) | Value::Err(_) => "result",
+            Value::Some(_) | Value::None => "option",
+            Value::Class(_) => "class",
+            Value::Trait(_) => "trait",
+            Value::Instance(_) => "instance",
+            Value::Generator(_) => "generator",
+            Value::Task(_) => "task",
+            Value::Channel(_) => "channel",
+            Value::Thread(_) => "thread",
+            Value::Module(_) => "module",
+            Value::Unit => "unit",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::BigInt(i) => write!(f, "{}", i),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Char(c) => write!(f, "{}", c),
+            Value::List(items) => write_once(f, Rc::as_ptr(items) as *const (), "[...]", |f| {
+                write!(f, "[")?;
+                for (i, item) in items.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_nested(f, item)?;
+                }
+                write!(f, "]")
+            }),
+            Value::Range(range) => write!(f, "{}", range),
+            Value::Function(func) => write!(f, "<function {}>", func.name),
+            Value::Builtin(name) => write!(f, "<built-in function {}>", name),
+            Value::Error { kind, message } => write!(f, "{}: {}", kind, message),
+            Value::Ok(value) => write_wrapped(f, "Ok", value),
+            Value::Err(value) => write_wrapped(f, "Err", value),
+            Value::Some(value) => write_wrapped(f, "Some", value),
+            Value::None => write!(f, "None"),
+            Value::Class(class) => write!(f, "<class {}>", class.name),
+            Value::Trait(t) => write!(f, "<trait {}>", t.name),
+            Value::Instance(instance) => {
+                let placeholder = format!("{} {{...}}", instance.borrow().class.name);
+                write_once(f, Rc::as_ptr(instance) as *const (), &placeholder, |f| {
+                    let instance = instance.borrow();
+                    write!(f, "{} {{", instance.class.name)?;
+                    for (i, (name, value)) in instance.fields.iter().enumerate() {
+                        write!(f, "{} {}: ", if i > 0 { "," } else { "" }, name)?;
+                        write_nested(f, value)?;
+                    }
+                    if instance.fields.is_empty() {
+                        write!(f, "}}")
+                    } else {
+                        write!(f, " }}")
+                    }
+                })
+            },
+            Value::Generator(generator) => write!(f, "{:?}", generator.borrow()),
+            Value::Task(task) => write!(f, "{:?}", task.borrow()),
+            Value::Channel(channel) => write!(f, "{:?}", channel),
+            Value::Thread(thread) => write!(f, "{:?}", thread.borrow()),
+            Value::Module(module) => write!(f, "<module {}>", module.name),
+            Value::Unit => write!(f, "Unit"),
+        }
+    }
+}
+
+// 他の値の中の文字列と文字は区別できるように引用符を付ける
+fn write_nested(f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+    match value {
+        Value::String(s) => write!(f, "{:?}", s),
+        Value::Char(c) => write!(f, "{:?}", c),
+        other => write!(f, "{}", other),
+    }
+}
+
+thread_local! {
+    // 表示している途中のリストとインスタンス
+    static DISPLAYING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+}
+
+// 自分自身を含むリストやインスタンスは、循環したところを placeholder で表示する
+fn write_once(f: &mut fmt::Formatter, ptr: *const (), placeholder: &str, write: impl FnOnce(&mut fmt::Formatter) -> fmt::Result) -> fmt::Result {
+    if DISPLAYING.with(|displaying| displaying.borrow().contains(&ptr)) {
+        return write!(f, "{}", placeholder);
+    }
+    DISPLAYING.with(|displaying| displaying.borrow_mut().push(ptr));
+    let result = write(f);
+    DISPLAYING.with(|displaying| displaying.borrow_mut().pop());
+    result
+}
+
+fn write_wrapped(f: &mut fmt::Formatter, name: &str, value: &Value) -> fmt::Result {
+    write!(f, "{}(", name)?;
+    write_nested(f, value)?;
+    write!(f, ")")
+}
+
+// 変数の値と、constで宣言されたかどうか
+pub struct Binding {
+    pub value: Value,
+    pub constant: bool,
+}
+
+// モジュールごとのスコープ
+// 関数はそれを定義したモジュールのスコープで実行されるので、モジュールのグローバル変数と関数は混ざらない
+struct ModuleScope {
+    functions: HashMap<String, Rc<Function>>,
+    // 変数のスコープを管理するスタック(先頭はモジュールのグローバルスコープ)
+    variable_stack: Vec<HashMap<String, Binding>>,
+    // 関数呼び出しごとのスコープの開始位置
+    frame_bases: Vec<usize>,
+    // pubを付けて公開した名前
+    exports: BTreeSet<String>,
+}
+
+impl ModuleScope {
+    fn new() -> Self {
+        ModuleScope {
+            functions: HashMap::new(),
+            variable_stack: vec![HashMap::new()],
+            frame_bases: Vec::new(),
+            exports: BTreeSet::new(),
         }
     }
 }
 
 pub struct Context {
-    //variables: HashMap<String, Value>,
-    functions: HashMap<String, (Vec<String>, Box<Expr>)>,    
-    // 変数のスコープを管理するスタック
-    variable_stack: Vec<HashMap<String, Value>>,
+    // 0番はメインのプログラム、importしたモジュールはその後に続く
+    modules: Vec<ModuleScope>,
+    // 評価中のモジュール
+    current: usize,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Context {
     pub fn new() -> Self {
         Context {
-            //variables: HashMap::new(),
-            functions: HashMap::new(),
-            variable_stack: vec![HashMap::new()],
+            modules: vec![ModuleScope::new()],
+            current: 0,
         }
     }
 
+    fn scope(&self) -> &ModuleScope {
+        &self.modules[self.current]
+    }
+
+    fn scope_mut(&mut self) -> &mut ModuleScope {
+        &mut self.modules[self.current]
+    }
+
+    // 新しいモジュールのスコープを作ってその番号を返す
+    pub fn add_module(&mut self) -> usize {
+        self.modules.push(ModuleScope::new());
+        self.modules.len() - 1
+    }
+
+    pub fn current_module(&self) -> usize {
+        self.current
+    }
+
+    // 評価するモジュールを切り替えて、それまでのモジュールを返す
+    pub fn enter_module(&mut self, module: usize) -> usize {
+        while self.modules.len() <= module {
+            self.modules.push(ModuleScope::new());
+        }
+        std::mem::replace(&mut self.current, module)
+    }
+
+    // モジュールのグローバル変数か関数を名前で探す
+    pub fn module_member(&self, module: usize, name: &str) -> Option<Value> {
+        let scope = self.modules.get(module)?;
+        match scope.variable_stack[0].get(name) {
+            Some(binding) => Some(binding.value.clone()),
+            None => scope.functions.get(name).cloned().map(Value::Function),
+        }
+    }
+
+    pub fn module_count(&self) -> usize {
+        self.modules.len()
+    }
+
+    // モジュールのグローバル変数
+    pub fn globals(&self, module: usize) -> impl Iterator<Item = (&String, &Binding)> {
+        self.modules.get(module).into_iter().flat_map(|scope| scope.variable_stack[0].iter())
+    }
+
+    // 評価中のモジュールの名前を公開する
+    pub fn export(&mut self, name: String) {
+        self.scope_mut().exports.insert(name);
+    }
+
+    // 公開されている名前(名前順)
+    pub fn exports(&self, module: usize) -> Vec<String> {
+        self.modules.get(module).map_or_else(Vec::new, |scope| scope.exports.iter().cloned().collect())
+    }
+
+    // モジュールのトップレベル(関数やブロックの外)で評価しているかどうか
+    pub fn at_top_level(&self) -> bool {
+        let scope = self.scope();
+        scope.frame_bases.is_empty() && scope.variable_stack.len() == 1
+    }
+
     pub fn push_scope(&mut self) {
-        self.variable_stack.push(HashMap::new());
+        self.scope_mut().variable_stack.push(HashMap::new());
     }
 
     pub fn pop_scope(&mut self) {
-        self.variable_stack.pop();
+        self.scope_mut().variable_stack.pop();
+    }
+
+    // 関数呼び出し用のスコープを作る
+    // 呼び出し元のローカル変数は見えず、グローバルスコープだけが見える
+    pub fn push_frame(&mut self) {
+        let scope = self.scope_mut();
+        scope.frame_bases.push(scope.variable_stack.len());
+        self.push_scope();
     }
 
-    pub fn set_variable(&mut self, name: String, value: Value) {
-        if let Some(current_scope) = self.variable_stack.last_mut() {
-            current_scope.insert(name, value);
+    // 関数の中で評価しているかどうか
+    pub fn in_function(&self) -> bool {
+        !self.scope().frame_bases.is_empty()
+    }
+
+    // 関数のフレームを取り外して返す(ジェネレーターを中断するとき)
+    pub fn take_frame(&mut self) -> Vec<HashMap<String, Binding>> {
+        let scope = self.scope_mut();
+        let base = scope.frame_bases.pop().unwrap_or(scope.variable_stack.len());
+        scope.variable_stack.split_off(base)
+    }
+
+    // 取り外したフレームを戻す(ジェネレーターを再開するとき)
+    pub fn restore_frame(&mut self, scopes: Vec<HashMap<String, Binding>>) {
+        let scope = self.scope_mut();
+        scope.frame_bases.push(scope.variable_stack.len());
+        scope.variable_stack.extend(scopes);
+    }
+
+    pub fn pop_frame(&mut self) {
+        let scope = self.scope_mut();
+        if let Some(base) = scope.frame_bases.pop() {
+            scope.variable_stack.truncate(base);
         }
     }
 
-    pub fn get_variable(&self, name: &str) -> Option<&Value> {
-        for scope in self.variable_stack.iter().rev() {
-            if let Some(val) = scope.get(name) {
-                return Some(val);
-            }
+    // 現在見えるスコープを内側から順に返す
+    fn visible_scopes(&self) -> impl Iterator<Item = usize> {
+        let scope = self.scope();
+        let base = scope.frame_bases.last().copied().unwrap_or(0);
+        let global = if base > 0 { Some(0) } else { None };
+        (base..scope.variable_stack.len()).rev().chain(global)
+    }
+
+    // 現在のスコープに新しい変数を宣言する(同じスコープの同名変数はシャドーイングされる)
+    pub fn declare_variable(&mut self, name: String, value: Value, constant: bool) {
+        if let Some(current_scope) = self.scope_mut().variable_stack.last_mut() {
+            current_scope.insert(name, Binding { value, constant });
         }
-        None
     }
 
-    pub fn set_function(&mut self, name: String, params: Vec<String>, body: Expr) {
-        self.functions.insert(name, (params, Box::new(body)));
+    // 一番近いスコープにある既存の変数を更新する
+    pub fn assign_variable(&mut self, name: &str, value: Value) -> Result<(), String> {
+        let index = self.visible_scopes()
+            .find(|&i| self.scope().variable_stack[i].contains_key(name))
+            .ok_or_else(|| format!("Variable '{}' is not declared", name))?;
+        let binding = self.scope_mut().variable_stack[index].get_mut(name).unwrap();
+        if binding.constant {
+            return Err(format!("Cannot assign to constant '{}'", name));
+        }
+        binding.value = value;
+        Ok(())
+    }
+
+    pub fn get_variable(&self, name: &str) -> Option<&Value> {
+        let stack = &self.scope().variable_stack;
+        self.visible_scopes()
+            .find_map(|i| stack[i].get(name))
+            .map(|binding| &binding.value)
+    }
+
+    pub fn set_function(&mut self, name: String, params: Vec<Param>, returns: Option<String>, body: Expr, kind: FunctionKind) {
+        let function = Function { name: name.clone(), params, returns, body, kind, module: self.current };
+        self.scope_mut().functions.insert(name, Rc::new(function));
+    }
+
+    pub fn get_function(&self, name: &str) -> Option<Rc<Function>> {
+        self.scope().functions.get(name).cloned()
+    }
+
+    // すべてのモジュールで定義済みの関数(新しいスレッドに引き継ぐとき)
+    pub fn functions(&self) -> impl Iterator<Item = &Rc<Function>> {
+        self.modules.iter().flat_map(|scope| scope.functions.values())
     }
 
-    pub fn get_function(&self, name: &str) -> Option<&(Vec<String>, Box<Expr>)> {
-        self.functions.get(name)
+    // 関数を定義したモジュールに加える(スレッドに引き継いだ関数を戻すとき)
+    pub fn define_function(&mut self, function: Function) {
+        let previous = self.enter_module(function.module);
+        self.scope_mut().functions.insert(function.name.clone(), Rc::new(function));
+        self.current = previous;
     }
 }
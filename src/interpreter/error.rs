@@ -0,0 +1,64 @@
+use std::fmt;
+use crate::interpreter::context::Value;
+
+// 実行時エラー
+// 組み込みのエラーも `throw` された値も、catchで受け取れる値として持つ
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub value: Value,
+}
+
+impl RuntimeError {
+    // 種類とメッセージを持つ組み込みのエラー
+    pub fn new(kind: &str, message: impl Into<String>) -> Self {
+        RuntimeError {
+            value: Value::Error {
+                kind: kind.to_string(),
+                message: message.into(),
+            },
+        }
+    }
+
+    // `throw` された任意の値
+    pub fn thrown(value: Value) -> Self {
+        RuntimeError { value }
+    }
+
+    // メッセージの前に状況を付け加える(種類はそのまま)
+    // `throw` された値はそのまま伝える
+    pub fn context(self, prefix: String) -> Self {
+        match self.value {
+            Value::Error { kind, message } => RuntimeError::new(&kind, format!("{}: {}", prefix, message)),
+            value => RuntimeError::thrown(value),
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        match &self.value {
+            Value::Error { kind, .. } => kind,
+            _ => "Exception",
+        }
+    }
+}
+
+// 種類を指定しないエラーはRuntimeErrorになる
+impl From<String> for RuntimeError {
+    fn from(message: String) -> Self {
+        RuntimeError::new("RuntimeError", message)
+    }
+}
+
+impl From<&str> for RuntimeError {
+    fn from(message: &str) -> Self {
+        RuntimeError::new("RuntimeError", message)
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Value::Error { message, .. } => write!(f, "{}", message),
+            other => write!(f, "Uncaught exception: {}", other),
+        }
+    }
+}
@@ -1,116 +1,1092 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::ControlFlow;
+use std::rc::Rc;
+use crate::interpreter::builtins;
 use crate::interpreter::context::*;
+use crate::interpreter::error::RuntimeError;
+use crate::interpreter::generator::{Generator, GeneratorState, Items, Receiver, Task};
+use crate::interpreter::module::{self, Module, ModuleLoader};
+use crate::interpreter::prelude;
+use crate::interpreter::scheduler::{AsyncTask, Scheduler, TaskKind, TaskResult};
+use crate::interpreter::thread::{SendModule, SendValue, ThreadHandle};
+use crate::interpreter::range::IntRange;
 use crate::parser::ast::*;
+use num_bigint::BigInt;
+use num_traits::Zero;
 
 pub struct Evaluator {
     ctx: Context,
+    // ブロックごとにdeferで登録された式
+    defer_stack: Vec<Vec<Expr>>,
+    // async関数やsleepのタスクを進めるイベントループ
+    scheduler: Scheduler,
+    // 埋め込む側のRustで定義した関数
+    host_functions: HashMap<String, HostFunction>,
+    // importしたモジュールの読み込みとキャッシュ
+    loader: ModuleLoader,
+    // どのモジュールからも使える標準の関数(読み込まなければNone)
+    prelude: Option<Rc<Module>>,
+}
+
+// ホスト関数(保留中の値を返すときは scheduler::pending を使う)
+pub type HostFunction = Rc<dyn Fn(Vec<Value>) -> Result<Value, RuntimeError>>;
+
+// 評価済みの位置引数と名前付き引数
+type Arguments = (Vec<Value>, Vec<(String, Value)>);
+
+// 比べている途中のリストを見分けるためのポインター
+type ListPtr = *const RefCell<Vec<Value>>;
+
+// ジェネレーターの文を一つ実行した結果
+enum GeneratorStep {
+    Continue,
+    Yield(Value),
+    // awaitで中断する(値は待つタスク)
+    Await(Value),
+    Return(Value),
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum EvaluationResult {
-    Value(Literal),
-    ReturnValue(Literal),
+    Value(Value),
+    ReturnValue(Value),
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Evaluator {
     pub fn new() -> Self {
-        Evaluator {
+        Evaluator::with_scheduler(Scheduler::new(), true)
+    }
+
+    // プレリュードを読み込まない(組み込み関数と自分で定義した関数だけを使う)
+    pub fn without_prelude() -> Self {
+        Evaluator::with_scheduler(Scheduler::new(), false)
+    }
+
+    // sleepが実際には待たずに時計を進める(結果を再現できるテスト用)
+    pub fn with_virtual_clock() -> Self {
+        Evaluator::with_scheduler(Scheduler::with_virtual_clock(), true)
+    }
+
+    fn with_scheduler(scheduler: Scheduler, prelude: bool) -> Self {
+        let mut evaluator = Evaluator {
             ctx: Context::new(),
+            defer_stack: Vec::new(),
+            scheduler,
+            host_functions: HashMap::new(),
+            loader: ModuleLoader::default(),
+            prelude: None,
+        };
+        if prelude {
+            let program = module::parse_source(prelude::SOURCE, "prelude").expect("The prelude must parse");
+            let module = evaluator.evaluate_module(program, "prelude", None).expect("The prelude must evaluate");
+            evaluator.prelude = Some(module);
         }
+        evaluator
+    }
+
+    // 実行するファイルのパスを設定する(importの相対パスはこのファイルのディレクトリから解決する)
+    pub fn set_main_path(&mut self, path: impl AsRef<std::path::Path>) {
+        self.loader.set_main_path(path.as_ref());
+    }
+
+    // スクリプトから呼べる関数をRustで定義する
+    pub fn define_host_function(
+        &mut self,
+        name: &str,
+        function: impl Fn(Vec<Value>) -> Result<Value, RuntimeError> + 'static,
+    ) {
+        self.host_functions.insert(name.to_string(), Rc::new(function));
+    }
+
+    // プログラム全体を評価する
+    // トップレベルの文は新しいスコープを作らずにグローバルスコープで評価する(REPLで変数を持ち越すため)
+    pub fn evaluate_program(&mut self, program: Expr) -> Result<EvaluationResult, RuntimeError> {
+        self.defer_stack.push(Vec::new());
+        let result = match program {
+            Expr::Block(statements) => self.evaluate_statements(statements),
+            expr => self.evaluate(expr),
+        };
+        // spawnしたタスクはプログラムが終わる前に最後まで実行する
+        // awaitされずに失敗したタスクがあれば、プログラムのエラーとして報告する
+        while self.run_once() {}
+        let result = match (result, self.scheduler.take_unawaited_error()) {
+            (Ok(_), Some(error)) => Err(error),
+            (result, _) => result,
+        };
+        self.run_deferred(result)
     }
 
-    pub fn evaluate(&mut self, expr: Expr) -> Result<EvaluationResult, String> {
+    pub fn evaluate(&mut self, expr: Expr) -> Result<EvaluationResult, RuntimeError> {
         match expr {
-            Expr::FunctionDef { name, params, body } => {
-                self.evaluate_function_def(name, params, *body)?;
-                Ok(EvaluationResult::Value(Literal::Unit))
+            Expr::FunctionDef { name, params, returns, body, kind, visibility } => {
+                self.export(&name, visibility)?;
+                self.evaluate_function_def(name, params, returns, *body, kind)?;
+                Ok(EvaluationResult::Value(Value::Unit))
             },
-            Expr::FunctionCall { name, args } => {
-                match self.evaluate_function_call(name, args)? {
+            Expr::FunctionCall { name, args, named_args } => {
+                match self.evaluate_function_call(name, args, named_args)? {
                     EvaluationResult::Value(val) => Ok(EvaluationResult::Value(val)),
                     EvaluationResult::ReturnValue(val) => Ok(EvaluationResult::ReturnValue(val)),
                 }
             },
+            Expr::MethodCall { receiver, method, args, named_args } => {
+                self.evaluate_method_call(*receiver, method, args, named_args)
+            },
+            Expr::Pipeline { input, stages } => self.evaluate_pipeline(*input, stages),
             Expr::IfExpr { condition, consequence, alternative } => {
-                self.evaluate_if_expr(*condition, Box::new((*consequence).clone()), alternative)
+                self.evaluate_if_expr(*condition, *consequence, alternative)
             },
             Expr::WhileLoop { condition, body } => {
                 self.evaluate_while_loop(*condition, *body)
             },
+            Expr::VariableDecl { name, value, constant, visibility } => {
+                self.export(&name, visibility)?;
+                self.evaluate_variable_decl(name, *value, constant)
+            },
+            Expr::ForIn { variable, iterable, body } => {
+                self.evaluate_for_in_loop(variable, *iterable, *body)
+            },
             Expr::Assignment { name, value } => {
                 match self.evaluate_assignment(name, *value)? {
                     EvaluationResult::Value(val) => Ok(EvaluationResult::Value(val)),
                     EvaluationResult::ReturnValue(val) => Ok(EvaluationResult::ReturnValue(val)),
                 }
             },
-            Expr::BinaryOp { left, op, right } => {
-                match self.evaluate_binary_op(*left, op, *right)? {
+            Expr::IndexAssignment { target, index, value } => {
+                self.evaluate_index_assignment(*target, *index, *value)
+            },
+            Expr::CompoundAssignment { target, op, value, line } => {
+                self.evaluate_compound_assignment(*target, op, *value, line)
+            },
+            Expr::BinaryOp { left, op, right, line } => {
+                match self.evaluate_binary_op(*left, op, *right, line)? {
                     EvaluationResult::Value(val) => Ok(EvaluationResult::Value(val)),
                     EvaluationResult::ReturnValue(val) => Ok(EvaluationResult::ReturnValue(val)),
                 }
             },
-            Expr::Literal(lit) => Ok(EvaluationResult::Value(lit)),
+            Expr::Literal(lit) => Ok(EvaluationResult::Value(Value::from_literal(lit)?)),
+            Expr::List(items) => self.evaluate_list(items),
+            Expr::Range { start, end, inclusive } => self.evaluate_range(*start, *end, inclusive),
+            Expr::Index { target, index } => self.evaluate_index(*target, *index),
             Expr::Variable(name) => {
                 let result = self.evaluate_variable(&name)?;
                 Ok(EvaluationResult::Value(result))
             },
             Expr::Block(expressions) => self.evaluate_block(expressions),
             Expr::Return(expr) => self.evaluate_return(*expr), 
+            Expr::Throw(expr) => self.evaluate_throw(*expr),
+            Expr::Propagate(expr) => self.evaluate_propagate(*expr),
+            Expr::Defer(expr) => self.evaluate_defer(*expr),
+            // ジェネレーターの本体はresume_generatorが進めるので、ここに来るのはそれ以外の場所
+            Expr::Yield(_) => Err("'yield' can only be used inside a generator function".into()),
+            Expr::Await(expr) => self.evaluate_await(*expr),
+            Expr::ClassDef { name, parent, methods, visibility } => {
+                self.export(&name, visibility)?;
+                self.evaluate_class_def(name, parent, methods)
+            },
+            Expr::TraitDef { name, required, defaults, visibility } => {
+                self.export(&name, visibility)?;
+                self.evaluate_trait_def(name, required, defaults)
+            },
+            Expr::ImplDef { trait_name, class_name, methods } => {
+                self.evaluate_impl_def(&trait_name, &class_name, methods)
+            },
+            Expr::Field { target, name } => self.evaluate_field(*target, &name),
+            Expr::FieldAssignment { target, name, value } => {
+                self.evaluate_field_assignment(*target, name, *value)
+            },
+            Expr::TryCatch { body, catch, finally } => self.evaluate_try_catch(*body, catch, finally),
+            Expr::Import { path, alias } => self.evaluate_import(&path, alias),
+            Expr::ImportNames { path, names } => self.evaluate_import_names(&path, names),
+            Expr::MacroDef { name, .. } | Expr::MacroCall { name, .. } => {
+                Err(format!("Macro '{}' must be expanded before evaluation", name).into())
+            },
         }
     }
     
-    fn evaluate_function_def(&mut self, name: String, params: Vec<String>, body: Expr) -> Result<Literal, String> {
+    fn evaluate_function_def(&mut self, name: String, params: Vec<Param>, returns: Option<String>, body: Expr, kind: FunctionKind) -> Result<Value, RuntimeError> {
+        let kind = match kind {
+            FunctionKind::Normal if body.contains_yield() => FunctionKind::Generator,
+            FunctionKind::Async if body.contains_yield() => {
+                return Err(format!("'yield' cannot be used in async function '{}'", name).into());
+            },
+            kind => kind,
+        };
         // 関数定義をコンテキストに保存
-        self.ctx.set_function(name, params, body);
-        Ok(Literal::Unit) // 特に値を返さないからUnit型を返す
+        self.ctx.set_function(name, params, returns, body, kind);
+        Ok(Value::Unit) // 特に値を返さないからUnit型を返す
     }
                 
-    fn evaluate_function_call(&mut self, name: String, args: Vec<Expr>) -> Result<EvaluationResult, String> {
-        if let Some((params, body)) = self.ctx.get_function(&name).map(|f| (f.0.clone(), f.1.clone())) {
-            if params.len() != args.len() {
-                return Err(format!("Expected {} arguments, got {}", params.len(), args.len()));
+    fn evaluate_function_call(
+        &mut self,
+        name: String,
+        args: Vec<Expr>,
+        named_args: Vec<(String, Expr)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let (values, named_values) = match self.evaluate_arguments(args, named_args)? {
+            Ok(arguments) => arguments,
+            Err(result) => return Ok(result),
+        };
+        self.call_by_name(&name, values, named_values)
+    }
+
+    // 引数は呼び出し元のスコープで評価する
+    // 引数の評価中にReturnValueが出た場合はそれをErr側で返す
+    fn evaluate_arguments(
+        &mut self,
+        args: Vec<Expr>,
+        named_args: Vec<(String, Expr)>,
+    ) -> Result<Result<Arguments, EvaluationResult>, RuntimeError> {
+        let mut values = Vec::new();
+        for arg in args {
+            match self.evaluate(arg)? {
+                EvaluationResult::Value(val) => values.push(val),
+                result @ EvaluationResult::ReturnValue(_) => return Ok(Err(result)),
+            }
+        }
+        let mut named_values = Vec::new();
+        for (arg_name, arg) in named_args {
+            match self.evaluate(arg)? {
+                EvaluationResult::Value(val) => named_values.push((arg_name, val)),
+                result @ EvaluationResult::ReturnValue(_) => return Ok(Err(result)),
             }
+        }
+        Ok(Ok((values, named_values)))
+    }
 
-            self.ctx.push_scope();
-            for (param, arg) in params.iter().zip(args.iter()) {
-                let arg_eval_result = self.evaluate(arg.clone())?;
-                match arg_eval_result {
-                    EvaluationResult::Value(val) => {
-                        self.ctx.set_variable(param.clone(), Value::from_literal(val)?);
+    // 名前から呼び出せる値を探す
+    // 変数(関数を受け取った引数など)、ユーザー定義関数、プレリュード、組み込み関数の順に探す
+    fn resolve_callable(&self, name: &str) -> Option<Value> {
+        if let Some(value) = self.ctx.get_variable(name) {
+            Some(value.clone())
+        } else if let Some(function) = self.ctx.get_function(name) {
+            Some(Value::Function(function))
+        } else if let Some(value) = self.prelude.as_ref().and_then(|prelude| self.exported_member(prelude, name).ok()) {
+            Some(value)
+        } else if self.host_functions.contains_key(name) || builtins::exists(name) {
+            Some(Value::Builtin(name.to_string()))
+        } else {
+            builtins::constant(name)
+        }
+    }
+
+    fn call_by_name(
+        &mut self,
+        name: &str,
+        values: Vec<Value>,
+        named_values: Vec<(String, Value)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        match self.resolve_callable(name) {
+            Some(callee) => self.call_value(callee, values, named_values),
+            None => Err(RuntimeError::new("NameError", format!("Function '{}' not found", name))),
+        }
+    }
+
+    // 関数値を呼び出す
+    fn call_value(
+        &mut self,
+        callee: Value,
+        values: Vec<Value>,
+        named_values: Vec<(String, Value)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        match callee {
+            Value::Function(function) => self.call_function(&function, values, named_values),
+            Value::Builtin(name) => {
+                if let Some((arg_name, _)) = named_values.first() {
+                    return Err(RuntimeError::new("TypeError", format!("Built-in function '{}' does not accept named argument '{}'", name, arg_name)));
+                }
+                if let Some(function) = self.host_functions.get(&name) {
+                    return function(values).map(EvaluationResult::Value);
+                }
+                if builtins::EVALUATOR_FUNCTIONS.contains(&name.as_str()) {
+                    return self.call_evaluator_function(&name, values);
+                }
+                match builtins::call(&name, values) {
+                    Some(result) => result.map(EvaluationResult::Value),
+                    None => Err(RuntimeError::new("NameError", format!("Function '{}' not found", name))),
+                }
+            },
+            Value::Class(class) => self.instantiate(class, values, named_values),
+            other => Err(RuntimeError::new("TypeError", format!("Value of type {} is not callable", other.type_name()))),
+        }
+    }
+
+    // インスタンスを作り、initがあれば引数を渡して呼び出す
+    fn instantiate(
+        &mut self,
+        class: Rc<Class>,
+        values: Vec<Value>,
+        named_values: Vec<(String, Value)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let instance = Value::Instance(Rc::new(RefCell::new(Instance {
+            class: class.clone(),
+            fields: BTreeMap::new(),
+        })));
+        match class.find_method("init") {
+            Some((init, owner)) => {
+                self.call_method(&init, &owner, instance.clone(), values, named_values)?;
+            },
+            None if values.is_empty() && named_values.is_empty() => {},
+            None => {
+                return Err(RuntimeError::new(
+                    "TypeError",
+                    format!("Class '{}' has no 'init' and takes no arguments", class.name),
+                ));
+            },
+        }
+        Ok(EvaluationResult::Value(instance))
+    }
+
+    // メソッドはselfを、親クラスがあればsuperも束縛して呼び出す
+    // superは呼び出し中のメソッドを定義したクラスの親になる
+    fn call_method(
+        &mut self,
+        function: &Function,
+        owner: &Class,
+        receiver: Value,
+        values: Vec<Value>,
+        named_values: Vec<(String, Value)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let mut bindings = vec![("self", receiver)];
+        if let Some(parent) = &owner.parent {
+            bindings.push(("super", Value::Class(parent.clone())));
+        }
+        self.call_function_with(function, bindings, values, named_values)
+    }
+
+    // 関数値の呼び出しやタスクの操作が必要な組み込み関数
+    fn call_evaluator_function(&mut self, name: &str, mut values: Vec<Value>) -> Result<EvaluationResult, RuntimeError> {
+        match name {
+            // map_err(result, f): Errの中身だけをfで変換する
+            "map_err" => {
+                if values.len() != 2 {
+                    return Err(RuntimeError::new("TypeError", format!("Built-in function 'map_err' expects 2 arguments, got {}", values.len())));
+                }
+                let f = values.pop().unwrap();
+                match values.remove(0) {
+                    Value::Err(error) => match self.call_value(f, vec![*error], vec![])? {
+                        EvaluationResult::Value(val) | EvaluationResult::ReturnValue(val) => {
+                            Ok(EvaluationResult::Value(Value::Err(Box::new(val))))
+                        },
                     },
-                    EvaluationResult::ReturnValue(_) => {
-                        self.ctx.pop_scope();
-                        return Ok(arg_eval_result);
+                    ok @ Value::Ok(_) => Ok(EvaluationResult::Value(ok)),
+                    other => Err(RuntimeError::new("TypeError", format!("'map_err' expects a result, got {}", other.type_name()))),
+                }
+            },
+            // next(generator): 次の値をSomeで返し、終わっていればNoneを返す
+            "next" => {
+                if values.len() != 1 {
+                    return Err(RuntimeError::new("TypeError", format!("Built-in function 'next' expects 1 argument, got {}", values.len())));
+                }
+                match values.remove(0) {
+                    Value::Generator(generator) => {
+                        let value = self.resume_generator(&generator)?;
+                        Ok(EvaluationResult::Value(value.map_or(Value::None, |v| Value::Some(Box::new(v)))))
                     },
+                    other => Err(RuntimeError::new("TypeError", format!("'next' expects a generator, got {}", other.type_name()))),
                 }
+            },
+            // spawn(task): タスクを開始して、終わるのを待たずにそのタスクを返す
+            "spawn" => match values.as_slice() {
+                [Value::Task(task)] => {
+                    self.scheduler.start(task);
+                    Ok(EvaluationResult::Value(Value::Task(task.clone())))
+                },
+                [other] => Err(RuntimeError::new("TypeError", format!("'spawn' expects a task, got {}", other.type_name()))),
+                _ => Err(RuntimeError::new("TypeError", format!("Built-in function 'spawn' expects 1 argument, got {}", values.len()))),
+            },
+            // sleep(ms): awaitするとmsミリ秒後に終わるタスク
+            "sleep" => match values.as_slice() {
+                [Value::Int(ms)] if *ms >= 0 => {
+                    Ok(EvaluationResult::Value(Value::Task(AsyncTask::new("sleep", TaskKind::Sleep(*ms as u64)))))
+                },
+                [Value::Int(ms)] => Err(RuntimeError::new("ValueError", format!("'sleep' expects a non-negative duration, got {}", ms))),
+                [other] => Err(RuntimeError::new("TypeError", format!("'sleep' expects an int, got {}", other.type_name()))),
+                _ => Err(RuntimeError::new("TypeError", format!("Built-in function 'sleep' expects 1 argument, got {}", values.len()))),
+            },
+            // thread_spawn(f, args...): 新しいスレッドで f(args...) を実行する
+            // 引数はSendValueとして深くコピーして渡す
+            "thread_spawn" => {
+                let function = match values.first() {
+                    Some(Value::Function(function)) => Function::clone(function),
+                    Some(other) => return Err(RuntimeError::new("TypeError", format!("'thread_spawn' expects a function, got {}", other.type_name()))),
+                    None => return Err(RuntimeError::new("TypeError", "Built-in function 'thread_spawn' expects at least 1 argument, got 0")),
+                };
+                let args = values[1..].iter().map(SendValue::from_value).collect::<Result<Vec<_>, _>>()?;
+                let functions: Vec<Function> = self.ctx.functions().map(|function| Function::clone(function)).collect();
+                let modules = self.send_modules();
+                let name = function.name.clone();
+                let prelude = self.prelude.is_some();
+                let handle = std::thread::Builder::new()
+                    .name(name.clone())
+                    .spawn(move || run_thread(prelude, functions, modules, function, args))
+                    .map_err(|e| RuntimeError::new("RuntimeError", format!("Cannot start thread '{}': {}", name, e)))?;
+                Ok(EvaluationResult::Value(Value::Thread(Rc::new(RefCell::new(ThreadHandle { name, handle: Some(handle) })))))
+            },
+            // now(): スケジューラーの時計のミリ秒
+            "now" => match values.len() {
+                0 => Ok(EvaluationResult::Value(Value::Int(self.scheduler.now() as i64))),
+                n => Err(RuntimeError::new("TypeError", format!("Built-in function 'now' expects 0 arguments, got {}", n))),
+            },
+            _ => Err(RuntimeError::new("NameError", format!("Function '{}' not found", name))),
+        }
+    }
+
+    // receiver.method(args) は組み込みメソッドを優先し、
+    // なければ method(receiver, args) として自由関数を呼び出す
+    fn evaluate_method_call(
+        &mut self,
+        receiver: Expr,
+        method: String,
+        args: Vec<Expr>,
+        named_args: Vec<(String, Expr)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        if receiver == Expr::Variable("super".to_string()) {
+            return self.evaluate_super_call(method, args, named_args);
+        }
+        let receiver = match self.evaluate(receiver)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        let (values, named_values) = match self.evaluate_arguments(args, named_args)? {
+            Ok(arguments) => arguments,
+            Err(result) => return Ok(result),
+        };
+        self.invoke_method(receiver, &method, values, named_values)
+    }
+
+    // super.method(args) は親クラスのメソッドを今のselfで呼び出す
+    fn evaluate_super_call(
+        &mut self,
+        method: String,
+        args: Vec<Expr>,
+        named_args: Vec<(String, Expr)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let (parent, receiver) = match (self.ctx.get_variable("super"), self.ctx.get_variable("self")) {
+            (Some(Value::Class(parent)), Some(receiver)) => (parent.clone(), receiver.clone()),
+            _ => {
+                return Err(RuntimeError::new(
+                    "NameError",
+                    "'super' can only be used in a method of a class that extends another class",
+                ));
+            },
+        };
+        let (function, owner) = parent.find_method(&method).ok_or_else(|| {
+            RuntimeError::new("TypeError", format!("No method '{}' in class '{}'", method, parent.name))
+        })?;
+        let (values, named_values) = match self.evaluate_arguments(args, named_args)? {
+            Ok(arguments) => arguments,
+            Err(result) => return Ok(result),
+        };
+        self.call_method(&function, &owner, receiver, values, named_values)
+    }
+
+    fn invoke_method(
+        &mut self,
+        receiver: Value,
+        method: &str,
+        values: Vec<Value>,
+        named_values: Vec<(String, Value)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        // モジュールはメンバーを呼び出す(math.square(2))
+        if let Value::Module(module) = &receiver {
+            let callee = self.module_member(module, method)?;
+            return self.call_value(callee, values, named_values);
+        }
+
+        // インスタンスはクラスのメソッド、関数を入れたフィールドの順に探す
+        if let Value::Instance(instance) = &receiver {
+            let class = instance.borrow().class.clone();
+            if let Some((function, owner)) = class.find_method(method) {
+                return self.call_method(&function, &owner, receiver, values, named_values);
             }
+            let field = instance.borrow().fields.get(method).cloned();
+            if let Some(callee) = field {
+                return self.call_value(callee, values, named_values);
+            }
+        }
 
-            let result = self.evaluate(*body)?;
-            self.ctx.pop_scope();
-            Ok(result)
-        } else {
-            Err(format!("Function '{}' not found", name))
+        // リストのcontainsは == と同じ規則で比べる
+        if let (Value::List(_), "contains", [item], true) = (&receiver, method, values.as_slice(), named_values.is_empty()) {
+            return self.contains(&receiver, item).map(|found| EvaluationResult::Value(Value::Int(found as i64)));
+        }
+        if let Some(result) = builtins::call_method(&receiver, method, &values) {
+            if let Some((arg_name, _)) = named_values.first() {
+                return Err(RuntimeError::new("TypeError", format!("Built-in method '{}' does not accept named argument '{}'", method, arg_name)));
+            }
+            return result.map(EvaluationResult::Value);
+        }
+
+        let callee = match self.resolve_callable(method) {
+            Some(callee @ (Value::Function(_) | Value::Builtin(_))) => callee,
+            _ => return Err(RuntimeError::new("TypeError", format!("No method '{}' for {}", method, receiver.type_name()))),
+        };
+        let mut ufcs_values = vec![receiver];
+        ufcs_values.extend(values);
+        self.call_value(callee, ufcs_values, named_values)
+    }
+
+    // 各段の呼び出しに前の段の結果を第一引数として渡す
+    // エラーはどの段で失敗したかを付けて返す
+    fn evaluate_pipeline(&mut self, input: Expr, stages: Vec<Expr>) -> Result<EvaluationResult, RuntimeError> {
+        let mut value = match self.evaluate(input)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+
+        for (number, stage) in stages.into_iter().enumerate() {
+            let label = match &stage {
+                Expr::FunctionCall { name, .. } | Expr::Variable(name) => name.clone(),
+                Expr::MethodCall { method, .. } => method.clone(),
+                other => return Err(format!("Pipeline stage {} is not a function call: {:?}", number + 1, other).into()),
+            };
+            value = match self.evaluate_pipeline_stage(stage, value) {
+                Ok(EvaluationResult::Value(val)) => val,
+                Ok(result @ EvaluationResult::ReturnValue(_)) => return Ok(result),
+                Err(e) => return Err(e.context(format!("Pipeline stage {} ('{}') failed", number + 1, label))),
+            };
+        }
+
+        Ok(EvaluationResult::Value(value))
+    }
+
+    fn evaluate_pipeline_stage(&mut self, stage: Expr, input: Value) -> Result<EvaluationResult, RuntimeError> {
+        match stage {
+            Expr::FunctionCall { name, args, named_args } => {
+                let (mut values, named_values) = match self.evaluate_arguments(args, named_args)? {
+                    Ok(arguments) => arguments,
+                    Err(result) => return Ok(result),
+                };
+                values.insert(0, input);
+                self.call_by_name(&name, values, named_values)
+            },
+            Expr::MethodCall { receiver, method, args, named_args } => {
+                let receiver = match self.evaluate(*receiver)? {
+                    EvaluationResult::Value(val) => val,
+                    result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+                };
+                let (mut values, named_values) = match self.evaluate_arguments(args, named_args)? {
+                    Ok(arguments) => arguments,
+                    Err(result) => return Ok(result),
+                };
+                values.insert(0, input);
+                self.invoke_method(receiver, &method, values, named_values)
+            },
+            Expr::Variable(name) => self.call_by_name(&name, vec![input], vec![]),
+            other => Err(format!("Not a function call: {:?}", other).into()),
+        }
+    }
+
+    // 実引数を仮引数に割り当ててから関数本体を評価する
+    fn call_function(
+        &mut self,
+        function: &Function,
+        values: Vec<Value>,
+        named_values: Vec<(String, Value)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        self.call_function_with(function, Vec::new(), values, named_values)
+    }
+
+    // bindingsは仮引数より先に関数のスコープに置く変数(メソッドのselfなど)
+    fn call_function_with(
+        &mut self,
+        function: &Function,
+        bindings: Vec<(&str, Value)>,
+        values: Vec<Value>,
+        named_values: Vec<(String, Value)>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let name = function.name.as_str();
+        let params = function.params.clone();
+        let positional_count = params.iter().filter(|p| !p.variadic).count();
+        let has_rest = params.iter().any(|p| p.variadic);
+        if values.len() > positional_count && !has_rest {
+            return Err(RuntimeError::new("TypeError", format!(
+                "Function '{}' expects at most {} arguments, got {}",
+                name, positional_count, values.len()
+            )));
+        }
+
+        // 位置引数を前から埋め、余りは可変長引数に回す
+        let mut bound: Vec<Option<Value>> = vec![None; params.len()];
+        let mut values = values.into_iter();
+        for (slot, _) in bound.iter_mut().zip(&params).filter(|(_, p)| !p.variadic) {
+            *slot = values.next();
+        }
+        let rest: Vec<Value> = values.collect();
+
+        for (arg_name, value) in named_values {
+            let index = params.iter()
+                .position(|p| p.name == arg_name && !p.variadic)
+                .ok_or_else(|| RuntimeError::new("TypeError", format!("Function '{}' has no parameter named '{}'", name, arg_name)))?;
+            if bound[index].is_some() {
+                return Err(RuntimeError::new("TypeError", format!("Function '{}' got multiple values for parameter '{}'", name, arg_name)));
+            }
+            bound[index] = Some(value);
+        }
+
+        // 本体は関数を定義したモジュールのスコープで実行する
+        let previous = self.ctx.enter_module(function.module);
+        self.ctx.push_frame();
+        for (binding, value) in bindings {
+            self.ctx.declare_variable(binding.to_string(), value, true);
+        }
+        let bound = self.bind_parameters(name, params, bound, rest);
+        if function.kind != FunctionKind::Normal && bound.is_ok() {
+            // 本体は実行せず、引数を束縛したフレームごとジェネレーターに預ける
+            let generator = self.create_generator(function);
+            self.ctx.enter_module(previous);
+            let value = match function.kind {
+                FunctionKind::Async => Value::Task(AsyncTask::new(&function.name, TaskKind::Coroutine(generator))),
+                _ => Value::Generator(generator),
+            };
+            return Ok(EvaluationResult::Value(value));
+        }
+        // returnは関数の境界で普通の値に戻す
+        // 戻り値の型は、クラスの名前を関数のモジュールで引けるようにフレームを戻す前に調べる
+        let result = bound
+            .and_then(|_| self.evaluate(function.body.clone()))
+            .and_then(|result| {
+                let (EvaluationResult::Value(value) | EvaluationResult::ReturnValue(value)) = result;
+                match &function.returns {
+                    Some(expected) if !self.has_type(&value, expected)? => Err(RuntimeError::new("TypeError", format!(
+                        "Function '{}' should return {}, got {}", name, expected, type_description(&value)
+                    ))),
+                    _ => Ok(value),
+                }
+            }); // エラーの場合もフレームを戻してから返す
+        self.ctx.pop_frame();
+        self.ctx.enter_module(previous);
+        result.map(EvaluationResult::Value)
+    }
+
+    // 型の注釈に値が合っているか
+    // 組み込みの型の名前(int, string, ...)とany、クラスとトレイトの名前が書ける
+    fn has_type(&self, value: &Value, annotation: &str) -> Result<bool, RuntimeError> {
+        if annotation == "any" {
+            return Ok(true);
         }
-    } 
+        if TYPE_NAMES.contains(&annotation) {
+            return Ok(value.type_name() == annotation);
+        }
+        let instance = match value {
+            Value::Instance(instance) => Some(instance.borrow()),
+            _ => None,
+        };
+        match self.resolve_callable(annotation) {
+            Some(Value::Class(class)) => Ok(instance.is_some_and(|instance| instance.class.is_subclass_of(&class))),
+            Some(Value::Trait(target)) => Ok(instance.is_some_and(|instance| instance.class.implements(&target))),
+            _ => Err(RuntimeError::new("TypeError", format!("Unknown type '{}' in annotation", annotation))),
+        }
+    }
+
+    // 既定値は関数のスコープで評価するので、前の仮引数を参照できる
+    fn bind_parameters(
+        &mut self,
+        name: &str,
+        params: Vec<Param>,
+        bound: Vec<Option<Value>>,
+        rest: Vec<Value>,
+    ) -> Result<(), RuntimeError> {
+        let mut rest = Some(rest);
+        for (param, value) in params.into_iter().zip(bound) {
+            let annotation = param.annotation.clone();
+            let value = match (value, param.default) {
+                _ if param.variadic => Value::list(rest.take().unwrap_or_default()),
+                (Some(value), _) => value,
+                (None, Some(default)) => match self.evaluate(default)? {
+                    EvaluationResult::Value(val) | EvaluationResult::ReturnValue(val) => val,
+                },
+                (None, None) => {
+                    return Err(RuntimeError::new("TypeError", format!("Missing argument '{}' in call to '{}'", param.name, name)));
+                },
+            };
+            // 可変長引数は要素ごとに型を調べる
+            if let Some(expected) = annotation {
+                let items = match &value {
+                    Value::List(items) if param.variadic => items.borrow().clone(),
+                    _ => vec![value.clone()],
+                };
+                for item in &items {
+                    if !self.has_type(item, &expected)? {
+                        return Err(RuntimeError::new("TypeError", format!(
+                            "Function '{}' expects parameter '{}' to be {}, got {}", name, param.name, expected, type_description(item)
+                        )));
+                    }
+                }
+            }
+            self.ctx.declare_variable(param.name, value, false);
+        }
+        Ok(())
+    }
+
+    // ジェネレーター関数の呼び出し
+    // 引数を束縛したフレームを取り外して預けておき、再開するたびに戻す
+    fn create_generator(&mut self, function: &Function) -> Rc<RefCell<Generator>> {
+        let statements = match function.body.clone() {
+            Expr::Block(statements) => statements,
+            body => vec![body],
+        };
+        self.ctx.push_scope();
+        let tasks = vec![Task::Block { statements: statements.into_iter(), deferred: Vec::new() }];
+        let scopes = self.ctx.take_frame();
+        Rc::new(RefCell::new(Generator {
+            name: function.name.clone(),
+            module: function.module,
+            state: GeneratorState::Suspended { scopes, tasks },
+        }))
+    }
+
+    // ジェネレーターを次のyieldまで進めて、その値を返す
+    // 本体を最後まで実行したかreturnした場合はNoneを返す
+    fn resume_generator(&mut self, generator: &Rc<RefCell<Generator>>) -> Result<Option<Value>, RuntimeError> {
+        match self.resume_coroutine(generator, None)? {
+            GeneratorStep::Yield(value) => Ok(Some(value)),
+            GeneratorStep::Await(_) => Err("'await' can only be used inside an async function".into()),
+            _ => Ok(None),
+        }
+    }
+
+    // 中断しているところから次のyieldかawaitまで実行する
+    // sentはawaitしていたタスクの結果
+    fn resume_coroutine(
+        &mut self,
+        generator: &Rc<RefCell<Generator>>,
+        sent: Option<TaskResult>,
+    ) -> Result<GeneratorStep, RuntimeError> {
+        let state = std::mem::replace(&mut generator.borrow_mut().state, GeneratorState::Running);
+        let (scopes, mut tasks) = match state {
+            GeneratorState::Suspended { scopes, tasks } => (scopes, tasks),
+            GeneratorState::Running => {
+                return Err(RuntimeError::new("ValueError", format!("Generator '{}' is already running", generator.borrow().name)));
+            },
+            GeneratorState::Done => {
+                generator.borrow_mut().state = GeneratorState::Done;
+                return Ok(GeneratorStep::Return(Value::Unit));
+            },
+        };
+
+        let module = generator.borrow().module;
+        let previous = self.ctx.enter_module(module);
+        self.ctx.restore_frame(scopes);
+        let result = match self.receive(&mut tasks, sent) {
+            Ok(Some(value)) => Ok(GeneratorStep::Return(value)),
+            Ok(None) => self.run_generator(&mut tasks),
+            Err(error) => Err(error),
+        };
+        if let Ok(GeneratorStep::Yield(_) | GeneratorStep::Await(_)) = result {
+            let scopes = self.ctx.take_frame();
+            self.ctx.enter_module(previous);
+            generator.borrow_mut().state = GeneratorState::Suspended { scopes, tasks };
+            return result;
+        }
+        // 終わったかエラーで抜けたので、残っているブロックのdeferを実行する
+        let closed = self.close_generator(tasks);
+        self.ctx.take_frame();
+        self.ctx.enter_module(previous);
+        generator.borrow_mut().state = GeneratorState::Done;
+        result.and_then(|step| closed.map(|_| step))
+    }
+
+    // awaitで中断していれば、受け取った結果を変数に入れる
+    // `return await` ならその値を返す
+    fn receive(
+        &mut self,
+        tasks: &mut Vec<Task>,
+        sent: Option<TaskResult>,
+    ) -> Result<Option<Value>, RuntimeError> {
+        let receiver = match tasks.pop() {
+            Some(Task::Receive(receiver)) => receiver,
+            other => {
+                tasks.extend(other);
+                return Ok(None);
+            },
+        };
+        let value = sent.unwrap_or(Ok(Value::Unit))?;
+        match receiver {
+            Receiver::Discard => {},
+            Receiver::Declare { name, constant } => self.ctx.declare_variable(name, value, constant),
+            Receiver::Assign(name) => self.ctx.assign_variable(&name, value)?,
+            Receiver::Return => return Ok(Some(value)),
+        }
+        Ok(None)
+    }
+
+    // 残っているタスクを次のyieldかawaitまで実行する
+    fn run_generator(&mut self, tasks: &mut Vec<Task>) -> Result<GeneratorStep, RuntimeError> {
+        loop {
+            let statement = match tasks.last_mut() {
+                None => return Ok(GeneratorStep::Return(Value::Unit)),
+                Some(Task::Block { statements, .. }) => match statements.next() {
+                    Some(statement) => statement,
+                    None => {
+                        if let Some(Task::Block { deferred, .. }) = tasks.pop() {
+                            self.finish_generator_block(deferred)?;
+                        }
+                        continue;
+                    },
+                },
+                Some(Task::While { condition, body }) => {
+                    let (condition, body) = (condition.clone(), body.clone());
+                    match self.evaluate_condition(condition)? {
+                        ControlFlow::Continue(true) => self.enter_generator_block(tasks, body, None),
+                        ControlFlow::Continue(false) => { tasks.pop(); },
+                        ControlFlow::Break(value) => return Ok(GeneratorStep::Return(value)),
+                    }
+                    continue;
+                },
+                Some(Task::ForIn { variable, items, body }) => {
+                    let (variable, body) = (variable.clone(), body.clone());
+                    match self.next_item(items)? {
+                        Some(item) => self.enter_generator_block(tasks, body, Some((variable, item))),
+                        None => { tasks.pop(); },
+                    }
+                    continue;
+                },
+                // 結果はresume_coroutineで受け取り済み
+                Some(Task::Receive(_)) => {
+                    tasks.pop();
+                    continue;
+                },
+            };
+            match self.run_generator_statement(tasks, statement)? {
+                GeneratorStep::Continue => {},
+                step => return Ok(step),
+            }
+        }
+    }
+
+    // ジェネレーターの本体の文を一つ実行する
+    // yieldやawaitを含む制御構造は再帰で評価せず、タスクとして積んで一歩ずつ進める
+    fn run_generator_statement(&mut self, tasks: &mut Vec<Task>, statement: Expr) -> Result<GeneratorStep, RuntimeError> {
+        let suspends = statement.contains_yield() || statement.contains_await();
+        match statement {
+            Expr::Yield(expr) => match self.evaluate(*expr)? {
+                EvaluationResult::Value(value) => Ok(GeneratorStep::Yield(value)),
+                EvaluationResult::ReturnValue(value) => Ok(GeneratorStep::Return(value)),
+            },
+            Expr::Await(expr) => self.suspend_on(tasks, *expr, Receiver::Discard),
+            Expr::VariableDecl { name, value, constant, .. } if suspends => {
+                self.suspend_on(tasks, awaited(*value), Receiver::Declare { name, constant })
+            },
+            Expr::Assignment { name, value } if suspends => self.suspend_on(tasks, awaited(*value), Receiver::Assign(name)),
+            Expr::Return(value) if suspends => self.suspend_on(tasks, awaited(*value), Receiver::Return),
+            // deferした式はジェネレーターのブロックが終わるときに実行する
+            Expr::Defer(expr) if !suspends => {
+                if let Some(Task::Block { deferred, .. }) = tasks.last_mut() {
+                    deferred.push(*expr);
+                }
+                Ok(GeneratorStep::Continue)
+            },
+            Expr::IfExpr { condition, consequence, alternative } if suspends => {
+                let branch = match self.evaluate_condition(*condition)? {
+                    ControlFlow::Continue(true) => Some(*consequence),
+                    ControlFlow::Continue(false) => alternative.map(|alt| *alt),
+                    ControlFlow::Break(value) => return Ok(GeneratorStep::Return(value)),
+                };
+                match branch {
+                    // else if は続けてif文として処理する
+                    Some(branch @ Expr::IfExpr { .. }) => self.run_generator_statement(tasks, branch),
+                    Some(branch) => {
+                        self.enter_generator_block(tasks, branch, None);
+                        Ok(GeneratorStep::Continue)
+                    },
+                    None => Ok(GeneratorStep::Continue),
+                }
+            },
+            Expr::WhileLoop { condition, body } if suspends => {
+                tasks.push(Task::While { condition: *condition, body: *body });
+                Ok(GeneratorStep::Continue)
+            },
+            Expr::ForIn { variable, iterable, body } if suspends => {
+                let items = match self.evaluate(*iterable)? {
+                    EvaluationResult::Value(Value::Generator(generator)) => Items::Generator(generator),
+                    EvaluationResult::Value(value) => Items::Values(iterate(value)?),
+                    EvaluationResult::ReturnValue(value) => return Ok(GeneratorStep::Return(value)),
+                };
+                tasks.push(Task::ForIn { variable, items, body: *body });
+                Ok(GeneratorStep::Continue)
+            },
+            block @ Expr::Block(_) if suspends => {
+                self.enter_generator_block(tasks, block, None);
+                Ok(GeneratorStep::Continue)
+            },
+            // 構文解析で弾いているが、マクロの展開で入り込むことがある
+            statement if suspends => {
+                let place = if matches!(statement, Expr::Defer(_)) { "defer" } else { "try/catch/finally" };
+                Err(if statement.contains_yield() {
+                    format!("'yield' cannot be used inside {}", place)
+                } else {
+                    format!("'await' cannot be used inside {} in an async function", place)
+                }.into())
+            },
+            statement => match self.evaluate(statement)? {
+                EvaluationResult::Value(_) => Ok(GeneratorStep::Continue),
+                EvaluationResult::ReturnValue(value) => Ok(GeneratorStep::Return(value)),
+            },
+        }
+    }
+
+    // awaitする値を評価して中断する(結果は再開したときにreceiverで受け取る)
+    fn suspend_on(&mut self, tasks: &mut Vec<Task>, expr: Expr, receiver: Receiver) -> Result<GeneratorStep, RuntimeError> {
+        match self.evaluate(expr)? {
+            EvaluationResult::Value(value) => {
+                tasks.push(Task::Receive(receiver));
+                Ok(GeneratorStep::Await(value))
+            },
+            EvaluationResult::ReturnValue(value) => Ok(GeneratorStep::Return(value)),
+        }
+    }
+
+    // ブロックに入る(ブロックごとにスコープを作る)
+    // bindingはfor-inのループ変数
+    fn enter_generator_block(&mut self, tasks: &mut Vec<Task>, body: Expr, binding: Option<(String, Value)>) {
+        self.ctx.push_scope();
+        if let Some((name, value)) = binding {
+            self.ctx.declare_variable(name, value, false);
+        }
+        let statements = match body {
+            Expr::Block(statements) => statements,
+            other => vec![other],
+        };
+        tasks.push(Task::Block { statements: statements.into_iter(), deferred: Vec::new() });
+    }
+
+    // ブロックを抜ける(deferした式を実行してからスコープを破棄する)
+    fn finish_generator_block(&mut self, deferred: Vec<Expr>) -> Result<(), RuntimeError> {
+        self.defer_stack.push(deferred);
+        let result = self.run_deferred(Ok(EvaluationResult::Value(Value::Unit)));
+        self.ctx.pop_scope();
+        result.map(|_| ())
+    }
+
+    // 内側のブロックから順に抜ける
+    // 先に起きたエラーを優先して返す
+    fn close_generator(&mut self, tasks: Vec<Task>) -> Result<(), RuntimeError> {
+        let mut result = Ok(());
+        for task in tasks.into_iter().rev() {
+            if let Task::Block { deferred, .. } = task {
+                if let Err(error) = self.finish_generator_block(deferred) {
+                    if result.is_ok() {
+                        result = Err(error);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    // for-inで次の要素を取り出す(ジェネレーターなら次のyieldまで進める)
+    fn next_item(&mut self, items: &mut Items) -> Result<Option<Value>, RuntimeError> {
+        match items {
+            Items::Values(values) => Ok(values.next()),
+            Items::Generator(generator) => self.resume_generator(generator),
+        }
+    }
+
+    // 条件式を評価する
+    // 条件式の中の `?` で関数を抜ける場合はBreakで戻り値を返す
+    fn evaluate_condition(&mut self, condition: Expr) -> Result<ControlFlow<Value, bool>, RuntimeError> {
+        match self.evaluate(condition)? {
+            EvaluationResult::Value(Value::Int(value)) => Ok(ControlFlow::Continue(value != 0)),
+            EvaluationResult::ReturnValue(value) => Ok(ControlFlow::Break(value)),
+            _ => Err(RuntimeError::new("TypeError", "Condition must be an integer")),
+        }
+    }
+
+    // taskが終わるまでイベントループを回す
+    fn run_until(&mut self, task: &Rc<RefCell<AsyncTask>>) -> Result<Value, RuntimeError> {
+        self.scheduler.start(task);
+        task.borrow_mut().awaited = true;
+        loop {
+            if let Some(result) = task.borrow().result() {
+                return result;
+            }
+            if !self.run_once() {
+                return Err(format!("Deadlock: task '{}' is waiting for something that never finishes", task.borrow().name).into());
+            }
+        }
+    }
+
+    // イベントループを一歩進める
+    // 再開できるコルーチンがなければ、ホストの値とsleepが終わるのを待つ
+    // 何も進められなければfalseを返す
+    fn run_once(&mut self) -> bool {
+        if let Some((task, sent)) = self.scheduler.ready.pop_front() {
+            self.resume_task(task, sent);
+            return true;
+        }
+        self.scheduler.poll_external() || self.scheduler.wait()
+    }
+
+    // async関数のタスクを次のawaitまで進める
+    // エラーはタスクの結果として持ち、awaitした側に渡す
+    fn resume_task(&mut self, task: Rc<RefCell<AsyncTask>>, sent: Option<TaskResult>) {
+        let generator = match &task.borrow().kind {
+            TaskKind::Coroutine(generator) => generator.clone(),
+            _ => return,
+        };
+        let result = match self.resume_coroutine(&generator, sent) {
+            Ok(GeneratorStep::Await(Value::Task(awaited))) => {
+                self.scheduler.start(&awaited);
+                awaited.borrow_mut().awaited = true;
+                let done = awaited.borrow().result();
+                match done {
+                    Some(result) => self.scheduler.ready.push_back((task, Some(result))),
+                    None => awaited.borrow_mut().waiters.push(task),
+                }
+                return;
+            },
+            Ok(GeneratorStep::Await(other)) => {
+                let error = RuntimeError::new("TypeError", format!("Cannot await {}", other.type_name()));
+                self.scheduler.ready.push_back((task, Some(Err(error))));
+                return;
+            },
+            Ok(GeneratorStep::Return(value)) => Ok(value),
+            Ok(_) => Err("'yield' cannot be used in an async function".into()),
+            Err(error) => Err(error),
+        };
+        self.scheduler.complete(&task, result);
+    }
+
+    // トップレベルのawaitは、タスクが終わるまでイベントループを回す
+    // async関数の中のawaitはrun_generator_statementで中断する
+    fn evaluate_await(&mut self, expr: Expr) -> Result<EvaluationResult, RuntimeError> {
+        if self.ctx.in_function() {
+            return Err("'await' must be at the top level or a statement of an async function ('await f()', 'let x = await f()', 'x = await f()' or 'return await f()')".into());
+        }
+        match self.evaluate(expr)? {
+            EvaluationResult::Value(Value::Task(task)) => self.run_until(&task).map(EvaluationResult::Value),
+            EvaluationResult::Value(other) => Err(RuntimeError::new("TypeError", format!("Cannot await {}", other.type_name()))),
+            result => Ok(result),
+        }
+    }
 
     fn evaluate_if_expr(
         &mut self, 
         condition: Expr, 
-        consequence: Box<Expr>, 
+        consequence: Expr, 
         alternative: Option<Box<Expr>>
-    ) -> Result<EvaluationResult, String> {
+    ) -> Result<EvaluationResult, RuntimeError> {
         let condition_result = self.evaluate(condition)?;
         match condition_result {
-            EvaluationResult::Value(Literal::Int(value)) => {
+            EvaluationResult::Value(Value::Int(value)) => {
                 if value != 0 {
-                    self.evaluate(*consequence)
+                    self.evaluate(consequence)
                 } else if let Some(alt) = alternative {
                     self.evaluate(*alt)
                 } else {
-                    Ok(EvaluationResult::Value(Literal::Int(0))) // if文にelse文がない場合
+                    Ok(EvaluationResult::Value(Value::Int(0))) // if文にelse文がない場合
                 }
             },
             EvaluationResult::ReturnValue(_) => Ok(condition_result),
-            _ => Err("Condition must be an integer".into()),
+            _ => Err(RuntimeError::new("TypeError", "Condition must be an integer")),
         }
     }
     
@@ -118,12 +1094,12 @@ impl Evaluator {
         &mut self, 
         condition: Expr, 
         body: Expr
-    ) -> Result<EvaluationResult, String> {
+    ) -> Result<EvaluationResult, RuntimeError> {
         //println!("Evaluating WhileLoop");
         loop {
             let condition_result = self.evaluate(condition.clone())?;
             match condition_result {
-                EvaluationResult::Value(Literal::Int(value)) => {
+                EvaluationResult::Value(Value::Int(value)) => {
                     if value == 0 {
                         break;
                     }
@@ -133,64 +1109,632 @@ impl Evaluator {
                     }
                 },
                 EvaluationResult::ReturnValue(_) => return Ok(condition_result),
-                _ => return Err("Condition must be an integer".into()),
+                _ => return Err(RuntimeError::new("TypeError", "Condition must be an integer")),
+            }
+        }
+        Ok(EvaluationResult::Value(Value::Int(0)))
+    }
+
+    fn evaluate_for_in_loop(
+        &mut self,
+        variable: String,
+        iterable: Expr,
+        body: Expr
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let iterable = match self.evaluate(iterable)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+
+        let mut items = match iterable {
+            Value::Generator(generator) => Items::Generator(generator),
+            other => Items::Values(iterate(other)?),
+        };
+        while let Some(item) = self.next_item(&mut items)? {
+            // ループ変数は繰り返しごとのスコープに置く
+            self.ctx.push_scope();
+            self.ctx.declare_variable(variable.clone(), item, false);
+            let body_result = self.evaluate(body.clone());
+            self.ctx.pop_scope();
+            if let result @ EvaluationResult::ReturnValue(_) = body_result? {
+                return Ok(result);
             }
         }
-        Ok(EvaluationResult::Value(Literal::Int(0)))
+        Ok(EvaluationResult::Value(Value::Unit))
     }
 
-    fn evaluate_assignment(&mut self, name: String, value: Expr) -> Result<EvaluationResult, String> {
+    fn evaluate_variable_decl(&mut self, name: String, value: Expr, constant: bool) -> Result<EvaluationResult, RuntimeError> {
         let eval_result = self.evaluate(value)?;
         match eval_result {
-            EvaluationResult::Value(val) => {
-                let value = Value::from_literal(val)?;
-                self.ctx.set_variable(name, value);
-                Ok(EvaluationResult::Value(Literal::Unit)) // 代入は値を返さないため、Unitを返す
+            EvaluationResult::Value(value) => {
+                self.ctx.declare_variable(name, value, constant);
+                Ok(EvaluationResult::Value(Value::Unit))
             },
             _ => Ok(eval_result),
         }
     }
 
-    fn evaluate_binary_op(&mut self, left: Expr, op: Op, right: Expr) -> Result<EvaluationResult, String> {
-        let left_result = self.evaluate(left)?;
-        let right_result = self.evaluate(right)?;
-
-        match (left_result, right_result) {
-            (EvaluationResult::Value(Literal::Int(l)), EvaluationResult::Value(Literal::Int(r))) => match op {
-                Op::Add => Ok(EvaluationResult::Value(Literal::Int(l + r))),
-                Op::Subtract => Ok(EvaluationResult::Value(Literal::Int(l - r))),
-                Op::Multiply => Ok(EvaluationResult::Value(Literal::Int(l * r))),
-                Op::Divide => Ok(EvaluationResult::Value(Literal::Int(l / r))),
-                Op::LessThan => Ok(EvaluationResult::Value(Literal::Int((l < r) as i64))),
-                Op::GreaterThan => Ok(EvaluationResult::Value(Literal::Int((l > r) as i64))),
+    fn evaluate_assignment(&mut self, name: String, value: Expr) -> Result<EvaluationResult, RuntimeError> {
+        let eval_result = self.evaluate(value)?;
+        match eval_result {
+            EvaluationResult::Value(value) => {
+                self.ctx.assign_variable(&name, value)?;
+                Ok(EvaluationResult::Value(Value::Unit)) // 代入は値を返さないため、Unitを返す
             },
-            _ => Err("Unsupported literal types for binary operation".into()),
+            _ => Ok(eval_result),
         }
     }
-    
-    fn evaluate_variable(&self, name: &str) -> Result<Literal, String> {
-        match self.ctx.get_variable(&name) {
-            Some(value) => match value {
-                Value::Int(i) => Ok(Literal::Int(*i)),
-                Value::String(s) => Ok(Literal::String(s.clone())),
-                // other type
-                _ => Err(format!("Unsupported value type for variable '{}'", name)),
+
+    fn evaluate_index_assignment(&mut self, target: Expr, index: Expr, value: Expr) -> Result<EvaluationResult, RuntimeError> {
+        let target = match self.evaluate(target)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        let index = match self.evaluate(index)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        let value = match self.evaluate(value)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        store_index(&target, &index, value)?;
+        Ok(EvaluationResult::Value(Value::Unit))
+    }
+
+    // 代入先の変数や添字式を一度だけ評価し、読み出し→演算→書き戻しを行う
+    fn evaluate_compound_assignment(&mut self, target: Expr, op: Op, value: Expr, line: usize) -> Result<EvaluationResult, RuntimeError> {
+        match target {
+            Expr::Variable(name) => {
+                let current = self.evaluate_variable(&name)?;
+                let rhs = match self.evaluate(value)? {
+                    EvaluationResult::Value(val) => val,
+                    result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+                };
+                let new_value = self.binary_op(current, &op, rhs, line)?;
+                self.ctx.assign_variable(&name, new_value)?;
+            },
+            Expr::Index { target, index } => {
+                let target = match self.evaluate(*target)? {
+                    EvaluationResult::Value(val) => val,
+                    result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+                };
+                let index = match self.evaluate(*index)? {
+                    EvaluationResult::Value(val) => val,
+                    result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+                };
+                let current = load_index(&target, &index)?;
+                let rhs = match self.evaluate(value)? {
+                    EvaluationResult::Value(val) => val,
+                    result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+                };
+                let new_value = self.binary_op(current, &op, rhs, line)?;
+                store_index(&target, &index, new_value)?;
             },
-            None => Err(format!("Variable '{}' not found", name)),
+            Expr::Field { target, name } => {
+                let target = match self.evaluate(*target)? {
+                    EvaluationResult::Value(val) => val,
+                    result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+                };
+                let current = load_field(&target, &name)?;
+                let rhs = match self.evaluate(value)? {
+                    EvaluationResult::Value(val) => val,
+                    result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+                };
+                let new_value = self.binary_op(current, &op, rhs, line)?;
+                store_field(&target, name, new_value)?;
+            },
+            other => return Err(format!("Invalid assignment target: {:?}", other).into()),
         }
+        Ok(EvaluationResult::Value(Value::Unit))
     }
-        
-    fn evaluate_block(&mut self, expressions: Vec<Expr>) -> Result<EvaluationResult, String> {
-        let mut result = EvaluationResult::Value(Literal::Unit); // デフォルトの結果をUnitとする
 
-        for expression in expressions {
-            result = self.evaluate(expression)?;
+    fn evaluate_binary_op(&mut self, left: Expr, op: Op, right: Expr, line: usize) -> Result<EvaluationResult, RuntimeError> {
+        let left = match self.evaluate(left)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        let right = match self.evaluate(right)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        self.binary_op(left, &op, right, line).map(EvaluationResult::Value)
+    }
+
+    // インスタンスが演算子のメソッドを持っていればそれを呼び、なければ組み込みの規則で計算する
+    // 組み込みの規則で0で割ったときは、エラーに演算子の行を付ける
+    fn binary_op(&mut self, left: Value, op: &Op, right: Value, line: usize) -> Result<Value, RuntimeError> {
+        // `x in c` はコンテナ側のメソッドを呼ぶ
+        if let Op::In = op {
+            if let Some(result) = self.call_operator_method(&right, operator_method(op), &left)? {
+                return Ok(result);
+            }
+            return self.contains(&right, &left).map(|found| Value::Int(found as i64));
+        }
+        if let Some(result) = self.call_operator_method(&left, operator_method(op), &right)? {
+            return Ok(result);
+        }
+        if let Some(name) = reflected_method(op) {
+            if let Some(result) = self.call_operator_method(&right, name, &left)? {
+                return Ok(result);
+            }
+        }
+        // != は __ne__ がなければ __eq__ を反転する
+        if let Op::NotEqual = op {
+            if let Some(result) = self.call_operator_method(&left, "__eq__", &right)? {
+                return Ok(Value::Int(matches!(result, Value::Int(0)) as i64));
+            }
+        }
+        match op {
+            Op::Equal => return self.values_equal(&left, &right).map(|equal| Value::Int(equal as i64)),
+            Op::NotEqual => return self.values_equal(&left, &right).map(|equal| Value::Int(!equal as i64)),
+            _ => {},
+        }
+        apply_binary_op(left, op, right).map_err(|e| match e.kind() {
+            "ZeroDivisionError" => e.context(self.location(line)),
+            _ => e,
+        })
+    }
+
+    // スレッドに引き継ぐ、importしたモジュールのグローバル変数(渡せないものは除く)と公開した名前
+    // メインのプログラムと、スレッドの評価器が自分で読み込むプレリュードは除く
+    fn send_modules(&self) -> Vec<SendModule> {
+        let prelude = self.prelude.as_ref().map(|module| module.id);
+        (1..self.ctx.module_count())
+            .filter(|&id| Some(id) != prelude)
+            .map(|id| SendModule {
+                id,
+                globals: self.ctx.globals(id)
+                    .filter_map(|(name, binding)| {
+                        let value = SendValue::from_value(&binding.value).ok()?;
+                        Some((name.clone(), value, binding.constant))
+                    })
+                    .collect(),
+                exports: self.ctx.exports(id),
+            })
+            .collect()
+    }
+
+    // エラーの位置。importしたモジュールの中ならモジュールのパスも付ける
+    fn location(&self, line: usize) -> String {
+        match self.loader.label(self.ctx.current_module()) {
+            Some(label) => format!("In module \"{}\", line {}", label, line),
+            None => format!("Line {}", line),
+        }
+    }
+
+    // インスタンスは __eq__ があればそれで、なければ同じものかどうかで比べる
+    // リストとSome/Ok/Errは中身を同じ規則で比べ、intとbigintは数として比べる
+    fn values_equal(&mut self, left: &Value, right: &Value) -> Result<bool, RuntimeError> {
+        self.values_equal_nested(left, right, &mut Vec::new())
+    }
+
+    // comparingは比べている途中のリストの組
+    // 循環したリストで同じ組にもう一度たどり着いたら、そこまでに違いはないので等しいとみなす
+    fn values_equal_nested(&mut self, left: &Value, right: &Value, comparing: &mut Vec<(ListPtr, ListPtr)>) -> Result<bool, RuntimeError> {
+        if let Some(result) = self.call_operator_method(left, "__eq__", right)? {
+            return Ok(!matches!(result, Value::Int(0)));
+        }
+        if let Some(result) = self.call_operator_method(right, "__eq__", left)? {
+            return Ok(!matches!(result, Value::Int(0)));
+        }
+        match (left, right) {
+            (Value::Instance(l), Value::Instance(r)) => Ok(Rc::ptr_eq(l, r)),
+            (Value::Int(i), Value::BigInt(big)) | (Value::BigInt(big), Value::Int(i)) => Ok(*big == BigInt::from(*i)),
+            (Value::List(l), Value::List(r)) => {
+                let pair = (Rc::as_ptr(l), Rc::as_ptr(r));
+                if comparing.contains(&pair) {
+                    return Ok(true);
+                }
+                // __eq__ がリストを書き換えても困らないよう写しを比べる
+                let (l, r) = (l.borrow().clone(), r.borrow().clone());
+                if l.len() != r.len() {
+                    return Ok(false);
+                }
+                comparing.push(pair);
+                for (l, r) in l.iter().zip(r.iter()) {
+                    if !self.values_equal_nested(l, r, comparing)? {
+                        comparing.pop();
+                        return Ok(false);
+                    }
+                }
+                comparing.pop();
+                Ok(true)
+            },
+            (Value::Some(l), Value::Some(r)) | (Value::Ok(l), Value::Ok(r)) | (Value::Err(l), Value::Err(r)) => {
+                self.values_equal_nested(l, r, comparing)
+            },
+            (l, r) => Ok(l == r),
+        }
+    }
+
+    fn contains(&mut self, container: &Value, item: &Value) -> Result<bool, RuntimeError> {
+        match (container, item) {
+            (Value::Range(range), Value::Int(i)) => Ok(range.contains(*i)),
+            (Value::Range(_), _) => Ok(false),
+            (Value::List(items), _) => {
+                let items = items.borrow().clone();
+                for candidate in &items {
+                    if self.values_equal(candidate, item)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            },
+            (Value::String(s), Value::String(sub)) => Ok(s.contains(sub.as_str())),
+            (Value::String(s), Value::Char(c)) => Ok(s.contains(*c)),
+            _ => Err(RuntimeError::new("TypeError", format!("Cannot check membership of {} in {}", item.type_name(), container.type_name()))),
+        }
+    }
+
+    fn call_operator_method(&mut self, receiver: &Value, name: &str, argument: &Value) -> Result<Option<Value>, RuntimeError> {
+        let class = match receiver {
+            Value::Instance(instance) => instance.borrow().class.clone(),
+            _ => return Ok(None),
+        };
+        match class.find_method(name) {
+            Some((function, owner)) => {
+                match self.call_method(&function, &owner, receiver.clone(), vec![argument.clone()], vec![])? {
+                    EvaluationResult::Value(val) | EvaluationResult::ReturnValue(val) => Ok(Some(val)),
+                }
+            },
+            None => Ok(None),
+        }
+    }
+    
+    // 変数がなければ同名の関数を関数値として返す
+    fn evaluate_variable(&self, name: &str) -> Result<Value, RuntimeError> {
+        match self.resolve_callable(name) {
+            Some(value) => Ok(value),
+            None => Err(RuntimeError::new("NameError", format!("Variable '{}' not found", name))),
+        }
+    }
+
+    fn evaluate_list(&mut self, items: Vec<Expr>) -> Result<EvaluationResult, RuntimeError> {
+        let mut values = Vec::new();
+        for item in items {
+            match self.evaluate(item)? {
+                EvaluationResult::Value(val) => values.push(val),
+                result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+            }
+        }
+        Ok(EvaluationResult::Value(Value::list(values)))
+    }
+
+    fn evaluate_range(&mut self, start: Expr, end: Expr, inclusive: bool) -> Result<EvaluationResult, RuntimeError> {
+        let start = match self.evaluate(start)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        let end = match self.evaluate(end)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        match (start, end) {
+            (Value::Int(start), Value::Int(end)) => {
+                Ok(EvaluationResult::Value(Value::Range(IntRange::new(start, end, inclusive))))
+            },
+            (start, end) => Err(RuntimeError::new("TypeError", format!("Range bounds must be ints, got {} and {}", start.type_name(), end.type_name()))),
+        }
+    }
+
+    fn evaluate_index(&mut self, target: Expr, index: Expr) -> Result<EvaluationResult, RuntimeError> {
+        let target = match self.evaluate(target)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        let index = match self.evaluate(index)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+
+        load_index(&target, &index).map(EvaluationResult::Value)
+    }
+        
+    // メソッドは関数としてまとめ、クラスを定数として宣言する
+    fn evaluate_class_def(
+        &mut self,
+        name: String,
+        parent: Option<String>,
+        methods: Vec<MethodDef>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let parent = match parent {
+            Some(parent_name) => match self.evaluate_variable(&parent_name)? {
+                Value::Class(class) => Some(class),
+                other => {
+                    return Err(RuntimeError::new(
+                        "TypeError",
+                        format!("Class '{}' cannot extend {} '{}'", name, other.type_name(), parent_name),
+                    ));
+                },
+            },
+            None => None,
+        };
+        let class = Class {
+            name: name.clone(),
+            parent,
+            methods: RefCell::new(method_table(methods, self.ctx.current_module())),
+            traits: RefCell::new(Vec::new()),
+        };
+        self.ctx.declare_variable(name, Value::Class(Rc::new(class)), true);
+        Ok(EvaluationResult::Value(Value::Unit))
+    }
+
+    fn evaluate_trait_def(
+        &mut self,
+        name: String,
+        required: Vec<String>,
+        defaults: Vec<MethodDef>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let definition = Trait {
+            name: name.clone(),
+            required,
+            defaults: method_table(defaults, self.ctx.current_module()),
+        };
+        self.ctx.declare_variable(name, Value::Trait(Rc::new(definition)), true);
+        Ok(EvaluationResult::Value(Value::Unit))
+    }
+
+    // implの時点で必要なメソッドがそろっているかを確かめ、
+    // クラスにメソッドと、クラスが持っていない既定の実装を加える
+    fn evaluate_impl_def(
+        &mut self,
+        trait_name: &str,
+        class_name: &str,
+        methods: Vec<MethodDef>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let definition = match self.evaluate_variable(trait_name)? {
+            Value::Trait(definition) => definition,
+            other => {
+                return Err(RuntimeError::new("TypeError", format!("'{}' is a {}, not a trait", trait_name, other.type_name())));
+            },
+        };
+        let class = match self.evaluate_variable(class_name)? {
+            Value::Class(class) => class,
+            other => {
+                return Err(RuntimeError::new("TypeError", format!("'{}' is a {}, not a class", class_name, other.type_name())));
+            },
+        };
+        if class.traits.borrow().iter().any(|t| Rc::ptr_eq(t, &definition)) {
+            return Err(RuntimeError::new(
+                "TypeError",
+                format!("Class '{}' already implements trait '{}'", class.name, definition.name),
+            ));
+        }
+
+        let methods = method_table(methods, self.ctx.current_module());
+        for required in &definition.required {
+            if !methods.contains_key(required) && class.find_method(required).is_none() {
+                return Err(RuntimeError::new(
+                    "TypeError",
+                    format!("Class '{}' does not implement '{}' required by trait '{}'", class.name, required, definition.name),
+                ));
+            }
+        }
+
+        class.methods.borrow_mut().extend(methods);
+        for (name, method) in &definition.defaults {
+            if class.find_method(name).is_none() {
+                class.methods.borrow_mut().insert(name.clone(), method.clone());
+            }
+        }
+        class.traits.borrow_mut().push(definition);
+        Ok(EvaluationResult::Value(Value::Unit))
+    }
+
+    fn evaluate_field(&mut self, target: Expr, name: &str) -> Result<EvaluationResult, RuntimeError> {
+        let target = match self.evaluate(target)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        if let Value::Module(module) = &target {
+            return self.module_member(module, name).map(EvaluationResult::Value);
+        }
+        Ok(EvaluationResult::Value(load_field(&target, name)?))
+    }
+
+    fn evaluate_field_assignment(&mut self, target: Expr, name: String, value: Expr) -> Result<EvaluationResult, RuntimeError> {
+        let target = match self.evaluate(target)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        let value = match self.evaluate(value)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        store_field(&target, name, value)?;
+        Ok(EvaluationResult::Value(Value::Unit))
+    }
+
+    // import "path" as name
+    fn evaluate_import(&mut self, path: &str, alias: String) -> Result<EvaluationResult, RuntimeError> {
+        let module = self.load_module(path)?;
+        self.ctx.declare_variable(alias, Value::Module(module), true);
+        Ok(EvaluationResult::Value(Value::Unit))
+    }
+
+    // from "path" import a, b
+    // 名前はimportした時点のメンバーの値に束縛される
+    fn evaluate_import_names(&mut self, path: &str, names: Vec<String>) -> Result<EvaluationResult, RuntimeError> {
+        let module = self.load_module(path)?;
+        for name in names {
+            let value = self.exported_member(&module, &name).map_err(|message| RuntimeError::new("ImportError", message))?;
+            self.ctx.declare_variable(name, value, true);
+        }
+        Ok(EvaluationResult::Value(Value::Unit))
+    }
+
+    // モジュールは最初にimportしたときに一度だけ評価し、その後はキャッシュを返す
+    fn load_module(&mut self, path: &str) -> Result<Rc<Module>, RuntimeError> {
+        let file = self.loader.resolve(self.ctx.current_module(), path)?;
+        if let Some(module) = self.loader.cached(&file) {
+            return Ok(module);
+        }
+        self.loader.begin(&file, path)?;
+        let result = module::parse_file(&file, path)
+            .and_then(|program| self.evaluate_module(program, &module::module_name(&file), Some((&file, path))));
+        self.loader.finish(&file, result.as_ref().ok().cloned());
+        result
+    }
+
+    // モジュールのトップレベルは、そのモジュールのグローバルスコープで評価する
+    // fileは(ファイルのパス, importに書いたパス)で、モジュールの中のimportの起点になる(プレリュードにはない)
+    fn evaluate_module(&mut self, program: Expr, name: &str, file: Option<(&std::path::Path, &str)>) -> Result<Rc<Module>, RuntimeError> {
+        let id = self.ctx.add_module();
+        if let Some((file, label)) = file {
+            self.loader.locate(id, file, label);
+        }
+        let previous = self.ctx.enter_module(id);
+        self.defer_stack.push(Vec::new());
+        let result = match program {
+            Expr::Block(statements) => self.evaluate_statements(statements),
+            expr => self.evaluate(expr),
+        };
+        let result = self.run_deferred(result);
+        self.ctx.enter_module(previous);
+        result?;
+        Ok(Rc::new(Module { name: name.to_string(), id }))
+    }
+
+    // pubを付けた定義をimportした側から使えるようにする
+    // モジュールの中のどこからでも使えるトップレベルの定義にしか付けられない
+    fn export(&mut self, name: &str, visibility: Visibility) -> Result<(), RuntimeError> {
+        if visibility == Visibility::Public {
+            if !self.ctx.at_top_level() {
+                return Err(RuntimeError::new("SyntaxError", format!("'pub' can only be used at the top level of a module, not on '{}'", name)));
+            }
+            self.ctx.export(name.to_string());
+        }
+        Ok(())
+    }
+
+    // importした側からはpubを付けたメンバーだけが見える
+    // 見えなければ、公開されている名前を並べたメッセージを返す
+    fn exported_member(&self, module: &Module, name: &str) -> Result<Value, String> {
+        let exports = self.ctx.exports(module.id);
+        match self.ctx.module_member(module.id, name) {
+            Some(value) if exports.iter().any(|export| export == name) => Ok(value),
+            found => {
+                let problem = if found.is_some() { "is private to" } else { "is not defined in" };
+                let listed = if exports.is_empty() { "nothing".to_string() } else { exports.join(", ") };
+                Err(format!("'{}' {} module '{}' (exported: {})", name, problem, module.name, listed))
+            },
+        }
+    }
+
+    fn module_member(&self, module: &Module, name: &str) -> Result<Value, RuntimeError> {
+        self.exported_member(module, name).map_err(|message| RuntimeError::new("AttributeError", message))
+    }
+
+    fn evaluate_block(&mut self, expressions: Vec<Expr>) -> Result<EvaluationResult, RuntimeError> {
+        // ブロックごとにスコープを作り、ブロック内で宣言した変数は `}` で破棄する
+        self.ctx.push_scope();
+        self.defer_stack.push(Vec::new());
+        let result = self.evaluate_statements(expressions);
+        // deferした式はブロックの変数が見えるうちに実行する
+        let result = self.run_deferred(result);
+        self.ctx.pop_scope();
+        result
+    }
+
+    // 式はその場では評価せず、囲んでいるブロックを抜けるときに評価する
+    fn evaluate_defer(&mut self, expr: Expr) -> Result<EvaluationResult, RuntimeError> {
+        match self.defer_stack.last_mut() {
+            Some(deferred) => {
+                deferred.push(expr);
+                Ok(EvaluationResult::Value(Value::Unit))
+            },
+            None => Err("'defer' can only be used inside a block".into()),
+        }
+    }
+
+    // 現在のブロックでdeferされた式を、登録と逆の順に実行する
+    // エラーで抜ける場合も実行し、先に起きたエラーを優先して返す
+    fn run_deferred(
+        &mut self,
+        mut result: Result<EvaluationResult, RuntimeError>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let deferred = self.defer_stack.pop().unwrap_or_default();
+        for expr in deferred.into_iter().rev() {
+            if let Err(error) = self.evaluate(expr) {
+                if result.is_ok() {
+                    result = Err(error);
+                }
+            }
+        }
+        result
+    }
+
+    fn evaluate_statements(&mut self, expressions: Vec<Expr>) -> Result<EvaluationResult, RuntimeError> {
+        let mut result = EvaluationResult::Value(Value::Unit); // デフォルトの結果をUnitとする
+
+        for expression in expressions {
+            result = self.evaluate(expression)?;
+            if let EvaluationResult::ReturnValue(_) = result {
+                break;
+            }
         }
 
         Ok(result) // ブロック内の最後の式の評価結果を返す
     }
 
-    fn evaluate_return(&mut self, expr: Expr) -> Result<EvaluationResult, String> {
+    // expr? はOk/Someなら中身を取り出し、Err/Noneならそれを関数の戻り値にする
+    // 早期リターンはreturnと同じくReturnValueで伝える
+    fn evaluate_propagate(&mut self, expr: Expr) -> Result<EvaluationResult, RuntimeError> {
+        let value = match self.evaluate(expr)? {
+            EvaluationResult::Value(val) => val,
+            result @ EvaluationResult::ReturnValue(_) => return Ok(result),
+        };
+        match value {
+            Value::Ok(inner) | Value::Some(inner) => Ok(EvaluationResult::Value(*inner)),
+            failure @ (Value::Err(_) | Value::None) => {
+                if !self.ctx.in_function() {
+                    return Err(RuntimeError::new(
+                        "TypeError",
+                        format!("'?' can only be used inside a function (got {})", failure),
+                    ));
+                }
+                Ok(EvaluationResult::ReturnValue(failure))
+            },
+            other => Err(RuntimeError::new("TypeError", format!("'?' expects a result or an option, got {}", other.type_name()))),
+        }
+    }
+
+    // 投げた値はエラーとして呼び出し元へ伝わり、try/catchで受け取れる
+    fn evaluate_throw(&mut self, expr: Expr) -> Result<EvaluationResult, RuntimeError> {
+        match self.evaluate(expr)? {
+            EvaluationResult::Value(val) => Err(RuntimeError::thrown(val)),
+            result @ EvaluationResult::ReturnValue(_) => Ok(result),
+        }
+    }
+
+    fn evaluate_try_catch(
+        &mut self,
+        body: Expr,
+        catch: Option<(String, Box<Expr>)>,
+        finally: Option<Box<Expr>>,
+    ) -> Result<EvaluationResult, RuntimeError> {
+        let mut result = self.evaluate(body);
+
+        if let (Err(error), Some((name, handler))) = (&result, catch) {
+            // 受け取ったエラーはcatchブロックのスコープでだけ見える
+            self.ctx.push_scope();
+            self.ctx.declare_variable(name, error.value.clone(), false);
+            result = self.evaluate(*handler);
+            self.ctx.pop_scope();
+        }
+
+        // finallyは常に実行する
+        // finallyの中のエラーとreturnは、それまでの結果より優先する
+        if let Some(finally) = finally {
+            if let result @ EvaluationResult::ReturnValue(_) = self.evaluate(*finally)? {
+                return Ok(result);
+            }
+        }
+        result
+    }
+
+    fn evaluate_return(&mut self, expr: Expr) -> Result<EvaluationResult, RuntimeError> {
         let val = self.evaluate(expr)?;
         match val {
             EvaluationResult::Value(val) => Ok(EvaluationResult::ReturnValue(val)),
@@ -200,10 +1744,322 @@ impl Evaluator {
 
 }
 
+fn apply_binary_op(left: Value, op: &Op, right: Value) -> Result<Value, RuntimeError> {
+    match (left, right) {
+        (Value::Int(l), Value::Int(r)) => int_op(l, op, r),
+        (Value::String(l), Value::String(r)) => match op {
+            Op::Add => Ok(Value::String(l + &r)),
+            Op::LessThan => Ok(Value::Int((l < r) as i64)),
+            Op::GreaterThan => Ok(Value::Int((l > r) as i64)),
+            Op::LessThanEqual => Ok(Value::Int((l <= r) as i64)),
+            Op::GreaterThanEqual => Ok(Value::Int((l >= r) as i64)),
+            _ => Err(RuntimeError::new("TypeError", "Unsupported literal types for binary operation")),
+        },
+        // bigintとintを混ぜた演算はbigintにそろえる
+        (Value::BigInt(l), Value::BigInt(r)) => big_int_op(l, op, r),
+        (Value::BigInt(l), Value::Int(r)) => big_int_op(l, op, BigInt::from(r)),
+        (Value::Int(l), Value::BigInt(r)) => big_int_op(BigInt::from(l), op, r),
+        // 文字はコードポイントの順に比べる
+        (Value::Char(l), Value::Char(r)) => match op {
+            Op::LessThan => Ok(Value::Int((l < r) as i64)),
+            Op::GreaterThan => Ok(Value::Int((l > r) as i64)),
+            Op::LessThanEqual => Ok(Value::Int((l <= r) as i64)),
+            Op::GreaterThanEqual => Ok(Value::Int((l >= r) as i64)),
+            Op::Add => Ok(Value::String(format!("{}{}", l, r))),
+            _ => Err(RuntimeError::new("TypeError", "Unsupported literal types for binary operation")),
+        },
+        (Value::String(l), Value::Char(r)) if *op == Op::Add => Ok(Value::String(format!("{}{}", l, r))),
+        (Value::Char(l), Value::String(r)) if *op == Op::Add => Ok(Value::String(format!("{}{}", l, r))),
+        _ => Err(RuntimeError::new("TypeError", "Unsupported literal types for binary operation")),
+    }
+}
+
+// intの演算は桁あふれを検査する(ビルドの種類によらず、あふれたらOverflowError)
+// 折り返しが必要なら wrapping_add などの組み込み関数を使う
+fn int_op(l: i64, op: &Op, r: i64) -> Result<Value, RuntimeError> {
+    let result = match op {
+        Op::Add => l.checked_add(r),
+        Op::Subtract => l.checked_sub(r),
+        Op::Multiply => l.checked_mul(r),
+        Op::Divide | Op::Modulo if r == 0 => return Err(zero_division(l, op, r)),
+        // i64::MIN / -1 もあふれる
+        Op::Divide => l.checked_div(r),
+        // 余りは -1 で割れば必ず0(checked_remは i64::MIN % -1 をあふれとして扱う)
+        Op::Modulo if r == -1 => Some(0),
+        Op::Modulo => l.checked_rem(r),
+        Op::LessThan => Some((l < r) as i64),
+        Op::GreaterThan => Some((l > r) as i64),
+        Op::LessThanEqual => Some((l <= r) as i64),
+        Op::GreaterThanEqual => Some((l >= r) as i64),
+        Op::In | Op::Equal | Op::NotEqual => return Err(not_arithmetic(op)),
+    };
+    result.map(Value::Int).ok_or_else(|| {
+        RuntimeError::new("OverflowError", format!("Integer overflow: {} {} {}", l, operator_symbol(op), r))
+    })
+}
+
+fn zero_division(l: impl std::fmt::Display, op: &Op, r: impl std::fmt::Display) -> RuntimeError {
+    let what = if *op == Op::Modulo { "Modulo" } else { "Division" };
+    RuntimeError::new("ZeroDivisionError", format!("{} by zero: {} {} {}", what, l, operator_symbol(op), r))
+}
+
+// ==, != と in は binary_op が値の比較として先に扱うので、数の演算には来ない
+fn not_arithmetic(op: &Op) -> RuntimeError {
+    RuntimeError::new("TypeError", format!("'{}' is not an arithmetic operator", operator_symbol(op)))
+}
+
+fn big_int_op(l: BigInt, op: &Op, r: BigInt) -> Result<Value, RuntimeError> {
+    if matches!(op, Op::Divide | Op::Modulo) && r.is_zero() {
+        return Err(zero_division(l, op, r));
+    }
+    match op {
+        Op::Add => Ok(Value::BigInt(l + r)),
+        Op::Subtract => Ok(Value::BigInt(l - r)),
+        Op::Multiply => Ok(Value::BigInt(l * r)),
+        Op::Divide => Ok(Value::BigInt(l / r)),
+        Op::Modulo => Ok(Value::BigInt(l % r)),
+        Op::LessThan => Ok(Value::Int((l < r) as i64)),
+        Op::GreaterThan => Ok(Value::Int((l > r) as i64)),
+        Op::LessThanEqual => Ok(Value::Int((l <= r) as i64)),
+        Op::GreaterThanEqual => Ok(Value::Int((l >= r) as i64)),
+        Op::In | Op::Equal | Op::NotEqual => Err(not_arithmetic(op)),
+    }
+}
+
+// 演算子を上書きするメソッドの名前
+fn operator_method(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "__add__",
+        Op::Subtract => "__sub__",
+        Op::Multiply => "__mul__",
+        Op::Divide => "__div__",
+        Op::Modulo => "__mod__",
+        Op::LessThan => "__lt__",
+        Op::GreaterThan => "__gt__",
+        Op::LessThanEqual => "__le__",
+        Op::GreaterThanEqual => "__ge__",
+        Op::Equal => "__eq__",
+        Op::NotEqual => "__ne__",
+        Op::In => "__contains__",
+    }
+}
+
+// エラーメッセージ用の演算子の記号
+fn operator_symbol(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "+",
+        Op::Subtract => "-",
+        Op::Multiply => "*",
+        Op::Divide => "/",
+        Op::Modulo => "%",
+        Op::LessThan => "<",
+        Op::GreaterThan => ">",
+        Op::LessThanEqual => "<=",
+        Op::GreaterThanEqual => ">=",
+        Op::Equal => "==",
+        Op::NotEqual => "!=",
+        Op::In => "in",
+    }
+}
+
+// 左辺がメソッドを持たないときに右辺で試すメソッドの名前
+// 比較は向きを入れ替えたものになる(a < b なら b.__gt__(a))
+fn reflected_method(op: &Op) -> Option<&'static str> {
+    match op {
+        Op::Add => Some("__radd__"),
+        Op::Subtract => Some("__rsub__"),
+        Op::Multiply => Some("__rmul__"),
+        Op::Divide => Some("__rdiv__"),
+        Op::Modulo => Some("__rmod__"),
+        Op::LessThan => Some("__gt__"),
+        Op::GreaterThan => Some("__lt__"),
+        Op::LessThanEqual => Some("__ge__"),
+        Op::GreaterThanEqual => Some("__le__"),
+        Op::Equal => Some("__eq__"),
+        Op::NotEqual => Some("__ne__"),
+        Op::In => None,
+    }
+}
+
+// `x in container`
+// 添字を位置に変換する
+fn position(len: usize, index: &Value, kind: &str) -> Result<usize, RuntimeError> {
+    match index {
+        Value::Int(i) => usize::try_from(*i).ok()
+            .filter(|&i| i < len)
+            .ok_or_else(|| RuntimeError::new("IndexError", format!("Index {} out of range for {} of length {}", i, kind, len))),
+        other => Err(RuntimeError::new("TypeError", format!("{} index must be an int or a range, got {}", kind, other.type_name()))),
+    }
+}
+
+// 添字の範囲を (開始, 終了) の位置に変換する。長さを超える部分は切り詰める
+fn slice_bounds(len: usize, range: &IntRange) -> Result<(usize, usize), RuntimeError> {
+    if range.step != 1 || range.start < 0 {
+        return Err(RuntimeError::new("ValueError", format!("Invalid slice {}", range)));
+    }
+    let start = (range.start as usize).min(len);
+    let end = if range.inclusive { range.end.saturating_add(1) } else { range.end };
+    let end = usize::try_from(end).unwrap_or(0).clamp(start, len);
+    Ok((start, end))
+}
+
+// 型のエラーで見せる値の型(インスタンスはクラスの名前)
+fn type_description(value: &Value) -> String {
+    match value {
+        Value::Instance(instance) => instance.borrow().class.name.clone(),
+        other => other.type_name().to_string(),
+    }
+}
+
+// 文字列の添字と範囲はコードポイント単位(バイト単位ではない)
+fn load_index(target: &Value, index: &Value) -> Result<Value, RuntimeError> {
+    match (target, index) {
+        (Value::List(items), Value::Range(range)) => {
+            let items = items.borrow();
+            let (start, end) = slice_bounds(items.len(), range)?;
+            Ok(Value::list(items[start..end].to_vec()))
+        },
+        (Value::List(items), _) => {
+            let items = items.borrow();
+            let position = position(items.len(), index, "list")?;
+            Ok(items[position].clone())
+        },
+        (Value::String(s), Value::Range(range)) => {
+            let chars: Vec<char> = s.chars().collect();
+            let (start, end) = slice_bounds(chars.len(), range)?;
+            Ok(Value::String(chars[start..end].iter().collect()))
+        },
+        (Value::String(s), _) => {
+            let chars: Vec<char> = s.chars().collect();
+            let position = position(chars.len(), index, "string")?;
+            Ok(Value::Char(chars[position]))
+        },
+        (Value::Range(range), Value::Range(slice)) => {
+            let (start, end) = slice_bounds(range.len(), slice)?;
+            Ok(Value::Range(range.slice(start, end)))
+        },
+        (Value::Range(range), _) => {
+            let position = position(range.len(), index, "range")?;
+            Ok(Value::Int(range.get(position).unwrap()))
+        },
+        _ => Err(RuntimeError::new("TypeError", format!("Cannot index {} with {}", target.type_name(), index.type_name()))),
+    }
+}
+
+fn store_index(target: &Value, index: &Value, value: Value) -> Result<(), RuntimeError> {
+    match target {
+        Value::List(items) => {
+            let mut items = items.borrow_mut();
+            let position = position(items.len(), index, "list")?;
+            items[position] = value;
+            Ok(())
+        },
+        _ => Err(RuntimeError::new("TypeError", format!("Cannot assign to an index of {}", target.type_name()))),
+    }
+}
+
+// thread_spawnで作ったスレッドの本体
+// スレッドごとに評価器を作り、定義済みの関数とimportしたモジュールのグローバル変数を引き継ぐ
+// (メインのプログラムのグローバル変数は引き継がない)
+// 関数と変数は元の評価器でのモジュールの番号のスコープに戻す
+// スレッドの評価器にない番号のスコープはenter_moduleが空のまま作るので、モジュールの番号は元の評価器と同じになる
+fn run_thread(prelude: bool, functions: Vec<Function>, modules: Vec<SendModule>, function: Function, args: Vec<SendValue>) -> Result<SendValue, SendValue> {
+    let mut evaluator = if prelude { Evaluator::new() } else { Evaluator::without_prelude() };
+    for f in functions {
+        evaluator.ctx.define_function(f);
+    }
+    for module in modules {
+        let previous = evaluator.ctx.enter_module(module.id);
+        for (name, value, constant) in module.globals {
+            evaluator.ctx.declare_variable(name, value.into_value(), constant);
+        }
+        for name in module.exports {
+            evaluator.ctx.export(name);
+        }
+        evaluator.ctx.enter_module(previous);
+    }
+    let args = args.into_iter().map(SendValue::into_value).collect();
+    let result = evaluator.call_function(&function, args, Vec::new());
+    while evaluator.run_once() {}
+    let result = match (result, evaluator.scheduler.take_unawaited_error()) {
+        (Ok(_), Some(error)) => Err(error),
+        (result, _) => result,
+    };
+    result
+        .and_then(|result| match result {
+            EvaluationResult::Value(value) | EvaluationResult::ReturnValue(value) => SendValue::from_value(&value),
+        })
+        .map_err(|error| {
+            // 投げられた値が渡せなければ、渡せないというエラーを代わりに返す
+            SendValue::from_value(&error.value).unwrap_or_else(|unsendable| SendValue::Error {
+                kind: "TypeError".to_string(),
+                message: unsendable.to_string(),
+            })
+        })
+}
+
+// `let x = await e` などの右辺からawaitを外す
+fn awaited(expr: Expr) -> Expr {
+    match expr {
+        Expr::Await(inner) => *inner,
+        other => other,
+    }
+}
+
+// メソッド定義を名前から引ける関数の表にする
+fn method_table(methods: Vec<MethodDef>, module: usize) -> HashMap<String, Rc<Function>> {
+    methods.into_iter()
+        .map(|m| {
+            let kind = if m.body.contains_yield() { FunctionKind::Generator } else { FunctionKind::Normal };
+            (m.name.clone(), Rc::new(Function { name: m.name, params: m.params, returns: m.returns, body: m.body, kind, module }))
+        })
+        .collect()
+}
+
+// インスタンスのフィールドを読む
+fn load_field(target: &Value, name: &str) -> Result<Value, RuntimeError> {
+    match target {
+        Value::Instance(instance) => {
+            let instance = instance.borrow();
+            instance.fields.get(name).cloned().ok_or_else(|| {
+                RuntimeError::new("AttributeError", format!("'{}' has no field '{}'", instance.class.name, name))
+            })
+        },
+        other => Err(RuntimeError::new("TypeError", format!("Cannot read field '{}' of {}", name, other.type_name()))),
+    }
+}
+
+// インスタンスのフィールドに書き込む(なければ作る)
+fn store_field(target: &Value, name: String, value: Value) -> Result<(), RuntimeError> {
+    match target {
+        Value::Instance(instance) => {
+            instance.borrow_mut().fields.insert(name, value);
+            Ok(())
+        },
+        other => Err(RuntimeError::new("TypeError", format!("Cannot set field '{}' of {}", name, other.type_name()))),
+    }
+}
+
+// for-in で回せる値の要素を順に返す
+// 範囲は要素を一つずつ計算するので、リストを作らない
+fn iterate(value: Value) -> Result<Box<dyn Iterator<Item = Value>>, RuntimeError> {
+    match value {
+        Value::Range(range) => Ok(Box::new((0..range.len()).map(move |i| Value::Int(range.get(i).unwrap())))),
+        Value::List(items) => Ok(Box::new(items.borrow().clone().into_iter())),
+        Value::String(s) => {
+            let chars: Vec<Value> = s.chars().map(Value::Char).collect();
+            Ok(Box::new(chars.into_iter()))
+        },
+        // チャンネルは閉じられるか送る側がいなくなるまで受け取る
+        Value::Channel(end) => Ok(Box::new(std::iter::from_fn(move || end.recv(None)))),
+        other => Err(RuntimeError::new("TypeError", format!("Cannot iterate over {}", other.type_name()))),
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use crate::parser::ast::{Expr, Literal, Op};
+    use crate::parser::ast::{Expr, FunctionKind, Literal, Op, Param};
 
     #[test]
     fn it_works() {
@@ -217,14 +2073,23 @@ mod tests {
             left: Box::new(Expr::Literal(Literal::Int(1))),
             op: Op::Add,
             right: Box::new(Expr::Literal(Literal::Int(2))),
+            line: 1,
         };
-        assert_eq!(evaluator.evaluate(expr), Ok(EvaluationResult::Value(Literal::Int(3))));
+        assert_eq!(evaluator.evaluate(expr), Ok(EvaluationResult::Value(Value::Int(3))));
     }
 
     // 変数の代入と参照をテスト
     #[test]
     fn test_variable_assignment_and_reference() {
         let mut evaluator = Evaluator::new();
+        // 宣言
+        let decl_expr = Expr::VariableDecl {
+            name: "x".to_string(),
+            value: Box::new(Expr::Literal(Literal::Int(1))),
+            constant: false,
+            visibility: Visibility::Private,
+        };
+        evaluator.evaluate(decl_expr).unwrap();
         // 代入
         let assign_expr = Expr::Assignment {
             name: "x".to_string(),
@@ -233,7 +2098,7 @@ mod tests {
         evaluator.evaluate(assign_expr).unwrap();
         // 参照
         let var_expr = Expr::Variable("x".to_string());
-        assert_eq!(evaluator.evaluate(var_expr), Ok(EvaluationResult::Value(Literal::Int(5))));
+        assert_eq!(evaluator.evaluate(var_expr), Ok(EvaluationResult::Value(Value::Int(5))));
     }
 
     // 関数定義と呼び出しをテスト
@@ -243,20 +2108,1525 @@ mod tests {
         // 関数定義
         let func_def_expr = Expr::FunctionDef {
             name: "add".to_string(),
-            params: vec!["a".to_string(), "b".to_string()],
+            params: vec![Param::new("a"), Param::new("b")],
             body: Box::new(Expr::BinaryOp {
                 left: Box::new(Expr::Variable("a".to_string())),
                 op: Op::Add,
                 right: Box::new(Expr::Variable("b".to_string())),
+                line: 1,
             }),
+            kind: FunctionKind::Normal,
+            visibility: Visibility::Private,
+            returns: None,
         };
         evaluator.evaluate(func_def_expr).unwrap();
         // 関数呼び出し
         let call_expr = Expr::FunctionCall {
             name: "add".to_string(),
             args: vec![Expr::Literal(Literal::Int(2)), Expr::Literal(Literal::Int(3))],
+            named_args: vec![],
         };
-        assert_eq!(evaluator.evaluate(call_expr), Ok(EvaluationResult::Value(Literal::Int(5))));
+        assert_eq!(evaluator.evaluate(call_expr), Ok(EvaluationResult::Value(Value::Int(5))));
+    }
+
+    pub(crate) fn run(source: &str) -> Result<EvaluationResult, String> {
+        run_with(&mut Evaluator::new(), source)
+    }
+
+    pub(crate) fn run_with(evaluator: &mut Evaluator, source: &str) -> Result<EvaluationResult, String> {
+        let tokens = crate::parser::lexer::tokenizer(source).expect("Tokenization failed");
+        let mut parser = crate::parser::Parser::new(tokens);
+        let ast = crate::parser::macros::Expander::new().expand_program(parser.parse_tokens()?)?;
+        evaluator.evaluate_program(ast).map_err(|e| e.to_string())
+    }
+
+    // 値を表示した文字列で比べる
+    pub(crate) fn display(result: Result<EvaluationResult, String>) -> Result<String, String> {
+        result.map(|r| match r {
+            EvaluationResult::Value(v) | EvaluationResult::ReturnValue(v) => v.to_string(),
+        })
+    }
+
+    // 未宣言の変数への代入はエラー
+    #[test]
+    fn test_assignment_to_undeclared_variable() {
+        assert_eq!(run("x = 1;"), Err("Variable 'x' is not declared".to_string()));
+    }
+
+    // constへの再代入はエラー
+    #[test]
+    fn test_constant_reassignment() {
+        assert_eq!(
+            run("const x = 1; x = 2;"),
+            Err("Cannot assign to constant 'x'".to_string())
+        );
+    }
+
+    // 関数の中からグローバル変数を更新できる
+    #[test]
+    fn test_function_updates_global() {
+        let source = "
+            let count = 0;
+            function inc() { count = count + 1; }
+            inc();
+            inc();
+            count;
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(2))));
+    }
+
+    // ブロック内で宣言した変数は `}` で消える
+    #[test]
+    fn test_block_scoping() {
+        let source = "
+            let x = 1;
+            if (1) { let x = 10; let y = 20; x = x + y; }
+            x;
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(
+            run("if (1) { let y = 20; } y;"),
+            Err("Variable 'y' not found".to_string())
+        );
+    }
+
+    // 関数から呼び出し元のローカル変数は見えない
+    #[test]
+    fn test_function_does_not_see_caller_locals() {
+        let source = "
+            function get() { return secret; }
+            function caller() { let secret = 1; get(); }
+            caller();
+        ";
+        assert_eq!(run(source), Err("Variable 'secret' not found".to_string()));
+    }
+
+    // 既定値つき引数
+    #[test]
+    fn test_default_parameters() {
+        let source = "
+            function f(x, y = x * 2) { return x + y; }
+            f(1) + f(1, 10);
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(14))));
+    }
+
+    // 名前付き引数
+    #[test]
+    fn test_named_arguments() {
+        let source = "
+            function f(x, y = 10, z = 100) { return x - y - z; }
+            f(1, z: 2);
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(-11))));
+        assert_eq!(
+            run("function f(x, y = 10) { x; } f(1, w: 2);"),
+            Err("Function 'f' has no parameter named 'w'".to_string())
+        );
+        assert_eq!(
+            run("function f(x, y = 10) { x; } f(1, 2, y: 3);"),
+            Err("Function 'f' got multiple values for parameter 'y'".to_string())
+        );
+        assert_eq!(
+            run("function f(x, y) { x; } f(y: 1);"),
+            Err("Missing argument 'x' in call to 'f'".to_string())
+        );
+    }
+
+    // 可変長引数は余った位置引数をリストで受け取る
+    #[test]
+    fn test_variadic_parameters() {
+        let source = "
+            function sum(first, ...rest) {
+                let total = first;
+                let i = 0;
+                while (i < len(rest)) {
+                    total = total + rest[i];
+                    i = i + 1;
+                }
+                return total;
+            }
+            sum(1) + sum(1, 2, 3, 4);
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(11))));
+        assert_eq!(
+            run("function f(...xs) { xs; } f();"),
+            Ok(EvaluationResult::Value(Value::list(vec![])))
+        );
+        assert_eq!(
+            run("function f(x) { x; } f(1, 2);"),
+            Err("Function 'f' expects at most 1 arguments, got 2".to_string())
+        );
+    }
+
+    // 組み込みメソッドとメソッドチェーン
+    #[test]
+    fn test_builtin_method_chain() {
+        assert_eq!(
+            run("let s = \"  Hello \"; s.trim().upper();"),
+            Ok(EvaluationResult::Value(Value::String("HELLO".to_string())))
+        );
+        assert_eq!(
+            run("\"a,b,c\".split(\",\").join(\"-\");"),
+            Ok(EvaluationResult::Value(Value::String("a-b-c".to_string())))
+        );
+        assert_eq!(
+            run("let xs = [1, 2]; xs.push(3); xs.len();"),
+            Ok(EvaluationResult::Value(Value::Int(3)))
+        );
     }
 
+    // 組み込みメソッドがなければ自由関数に第一引数として渡す
+    #[test]
+    fn test_uniform_function_call() {
+        let source = "
+            function add(x, y) { return x + y; }
+            let n = 1;
+            n.add(2).add(3);
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(6))));
+        assert_eq!(
+            run("let xs = [1, 2, 3]; xs.len() + len(xs);"),
+            Ok(EvaluationResult::Value(Value::Int(6)))
+        );
+        assert_eq!(
+            run("(12).str().len();"),
+            Ok(EvaluationResult::Value(Value::Int(2)))
+        );
+        assert_eq!(run("(1).nothing();"), Err("No method 'nothing' for int".to_string()));
+    }
+
+    // 複合代入と ++ / --
+    #[test]
+    fn test_compound_assignment() {
+        let source = "
+            let i = 10;
+            i += 5;
+            i -= 3;
+            i *= 2;
+            i /= 4;
+            i %= 4;
+            i++;
+            i++;
+            i--;
+            i;
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(3))));
+        assert_eq!(
+            run("const c = 1; c += 1;"),
+            Err("Cannot assign to constant 'c'".to_string())
+        );
+    }
+
+    // 添字の対象と添字の式は一度だけ評価される
+    #[test]
+    fn test_compound_assignment_evaluates_target_once() {
+        let source = "
+            let calls = 0;
+            let xs = [10, 20];
+            function pick() { calls++; return 1; }
+            xs[pick()] += 5;
+            xs[0] = 7;
+            xs[0] + xs[1] + (calls * 100);
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(132))));
+    }
+
+    // 関数は値として渡して呼び出せる
+    #[test]
+    fn test_functions_as_values() {
+        let source = "
+            function apply(f, x) { return f(x); }
+            function double(x) { return x * 2; }
+            let g = double;
+            apply(double, 3) + g(1) + apply(len, [1, 2, 3]);
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(11))));
+    }
+
+    // |> は左の値を右の呼び出しの第一引数にする
+    #[test]
+    fn test_pipeline() {
+        let source = "
+            function map(xs, f) {
+                let out = [];
+                let i = 0;
+                while (i < len(xs)) { out.push(f(xs[i])); i++; }
+                return out;
+            }
+            function filter(xs, keep) {
+                let out = [];
+                let i = 0;
+                while (i < len(xs)) { if (keep(xs[i])) { out.push(xs[i]); } i++; }
+                return out;
+            }
+            function sum(xs) {
+                let total = 0;
+                let i = 0;
+                while (i < len(xs)) { total += xs[i]; i++; }
+                return total;
+            }
+            function square(x) { return x * x; }
+            function odd(x) { return x % 2; }
+            [1, 2, 3, 4] |> map(square) |> filter(odd) |> sum();
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(10))));
+        assert_eq!(
+            run("let s = 1234 |> str |> len; s;"),
+            Ok(EvaluationResult::Value(Value::Int(4)))
+        );
+    }
+
+    // エラーは失敗した段を指す
+    #[test]
+    fn test_pipeline_error_names_stage() {
+        let source = "
+            function inc(x) { return x + 1; }
+            1 |> inc() |> missing(2) |> inc;
+        ";
+        assert_eq!(
+            run(source),
+            Err("Pipeline stage 2 ('missing') failed: Function 'missing' not found".to_string())
+        );
+    }
+
+    // 演算子の優先順位
+    #[test]
+    fn test_operator_precedence() {
+        assert_eq!(run("1 + 2 * 3 - 8 / 4;"), Ok(EvaluationResult::Value(Value::Int(5))));
+        assert_eq!(run("(1 + 2) * 3;"), Ok(EvaluationResult::Value(Value::Int(9))));
+    }
+
+    // 範囲は値として扱え、添字と部分範囲を取れる
+    #[test]
+    fn test_ranges() {
+        assert_eq!(
+            run("let r = 1..=10; r;"),
+            Ok(EvaluationResult::Value(Value::Range(IntRange::new(1, 10, true))))
+        );
+        assert_eq!(run("(0..10)[3];"), Ok(EvaluationResult::Value(Value::Int(3))));
+        assert_eq!(run("(0..10).step(3).len();"), Ok(EvaluationResult::Value(Value::Int(4))));
+        assert_eq!(
+            run("(0..100).step(10)[2..4].to_list();"),
+            Ok(EvaluationResult::Value(Value::list(vec![Value::Int(20), Value::Int(30)])))
+        );
+        assert_eq!(run("(0..3)[3];"), Err("Index 3 out of range for range of length 3".to_string()));
+        assert_eq!(run("(0..3).step(0);"), Err("Range step cannot be zero".to_string()));
+    }
+
+    // 巨大な範囲もリストを作らずに扱える
+    #[test]
+    fn test_huge_range_is_lazy() {
+        let source = "
+            let r = 0..9000000000000000000;
+            let total = 0;
+            for (i in r) {
+                if (i > 3) { return total; }
+                total += i;
+            }
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::ReturnValue(Value::Int(6))));
+        assert_eq!(
+            run("let r = 0..9000000000000000000; r[8999999999999999999] - len(r);"),
+            Ok(EvaluationResult::Value(Value::Int(-1)))
+        );
+        // intに収まらない要素数はOverflowErrorになるが、要素かどうかは両端で判定できる
+        let full = "(0 - 9223372036854775807 - 1)..=9223372036854775807";
+        assert_eq!(run(&format!("9223372036854775807 in {};", full)), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(
+            run("len((0 - 9223372036854775807 - 1)..9223372036854775807);"),
+            Err("Integer overflow: len(-9223372036854775808..9223372036854775807)".to_string())
+        );
+        assert_eq!(
+            run(&format!("({}).len();", full)),
+            Err("Integer overflow: (-9223372036854775808..=9223372036854775807).len()".to_string())
+        );
+    }
+
+    // for-in はリスト、範囲、文字列を回せる
+    #[test]
+    fn test_for_in_loop() {
+        let source = "
+            let total = 0;
+            for (i in 1..=4) { total += i; }
+            for (x in [10, 20]) { total += x; }
+            for (c in \"abc\") { total += c.byte_len(); }
+            total;
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(43))));
+        assert_eq!(run("for (i in 0..3) { let j = i; } j;"), Err("Variable 'j' not found".to_string()));
+    }
+
+    // in による所属判定とスライス
+    #[test]
+    fn test_membership_and_slicing() {
+        assert_eq!(run("5 in 1..10;"), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(run("10 in 1..10;"), Ok(EvaluationResult::Value(Value::Int(0))));
+        assert_eq!(run("10 in 1..=10;"), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(run("2 in [1, 2, 3];"), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(
+            run("[1, 2, 3, 4][1..3];"),
+            Ok(EvaluationResult::Value(Value::list(vec![Value::Int(2), Value::Int(3)])))
+        );
+        assert_eq!(
+            run("\"simlang\"[3..=6];"),
+            Ok(EvaluationResult::Value(Value::String("lang".to_string())))
+        );
+    }
+
+    // throwした値をcatchで受け取る
+    #[test]
+    fn test_throw_and_catch() {
+        let source = "
+            function check(x) {
+                if (x < 0) { throw \"negative\"; }
+                return x;
+            }
+            let caught = \"\";
+            try { check(0 - 1); } catch (e) { caught = e; }
+            caught;
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::String("negative".to_string()))));
+        assert_eq!(run("throw 42;"), Err("Uncaught exception: 42".to_string()));
+    }
+
+    // 組み込みのエラーも種類とメッセージを持つ値として受け取れる
+    #[test]
+    fn test_builtin_errors_are_catchable() {
+        let kind_of = |body: &str| {
+            run(&format!("let k = \"\"; try {{ {} }} catch (e) {{ k = e.kind(); }} k;", body))
+        };
+        let kind = |k: &str| Ok(EvaluationResult::Value(Value::String(k.to_string())));
+        assert_eq!(kind_of("missing;"), kind("NameError"));
+        assert_eq!(kind_of("[1, 2][5];"), kind("IndexError"));
+        assert_eq!(kind_of("1 + \"a\";"), kind("TypeError"));
+        assert_eq!(kind_of("int(\"abc\");"), kind("ValueError"));
+        assert_eq!(kind_of("throw error(\"Custom\", \"boom\");"), kind("Custom"));
+        assert_eq!(
+            run("let m = \"\"; try { [].pop(); } catch (e) { m = e.message(); } m;"),
+            Ok(EvaluationResult::Value(Value::String("pop() on an empty list".to_string())))
+        );
+    }
+
+    // finallyは正常時もエラー時も実行され、捕まえなかったエラーはそのまま伝わる
+    #[test]
+    fn test_finally() {
+        let source = "
+            let log = [];
+            try { log.push(1); } finally { log.push(2); }
+            try { try { missing; } finally { log.push(3); } } catch (e) { log.push(4); }
+            log;
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::list(vec![Value::Int(1), Value::Int(2), Value::Int(3), Value::Int(4)])))
+        );
+        assert_eq!(
+            run("try { missing; } finally { 1; }"),
+            Err("Variable 'missing' not found".to_string())
+        );
+        // catchの中で投げ直したエラーも外へ伝わる
+        assert_eq!(run("try { throw 1; } catch (e) { throw e + 1; }"), Err("Uncaught exception: 2".to_string()));
+    }
+
+    // エラーで抜けた関数のフレームとスコープは元に戻る
+    #[test]
+    fn test_error_unwinds_scopes() {
+        let source = "
+            let x = 1;
+            function fail() { let x = 2; if (1) { let y = 3; throw y; } }
+            try { fail(); } catch (e) { x = x + e; }
+            x;
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(4))));
+    }
+
+    // Ok/Err/Some/Noneと補助関数
+    #[test]
+    fn test_result_and_option_values() {
+        assert_eq!(run("Ok(1);"), Ok(EvaluationResult::Value(Value::Ok(Box::new(Value::Int(1))))));
+        assert_eq!(run("Err(\"bad\").unwrap_or(5);"), Ok(EvaluationResult::Value(Value::Int(5))));
+        assert_eq!(run("Some(3).unwrap_or(5);"), Ok(EvaluationResult::Value(Value::Int(3))));
+        assert_eq!(run("None.is_none();"), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(run("is_ok(Err(1));"), Ok(EvaluationResult::Value(Value::Int(0))));
+        assert_eq!(run("str(Err(\"x\"));"), Ok(EvaluationResult::Value(Value::String("Err(\"x\")".to_string()))));
+        assert_eq!(run("None.unwrap();"), Err("Called unwrap on None".to_string()));
+        let source = "
+            function describe(e) { return e.upper(); }
+            Err(\"io\").map_err(describe);
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::Err(Box::new(Value::String("IO".to_string())))))
+        );
+        assert_eq!(run("map_err(Ok(1), str);"), Ok(EvaluationResult::Value(Value::Ok(Box::new(Value::Int(1))))));
+    }
+
+    // ? はErr/Noneで関数から早期リターンする
+    #[test]
+    fn test_question_mark_propagation() {
+        let source = "
+            function parse(s) {
+                if (s < 0) { return Err(\"negative\"); }
+                return Ok(s * 2);
+            }
+            function total(a, b) {
+                let x = parse(a)?;
+                let y = parse(b)? + 1;
+                return Ok(x + y);
+            }
+            [total(1, 2), total(0 - 1, 2), total(1, 0 - 2)];
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::list(vec![
+                Value::Ok(Box::new(Value::Int(7))),
+                Value::Err(Box::new(Value::String("negative".to_string()))),
+                Value::Err(Box::new(Value::String("negative".to_string()))),
+            ])))
+        );
+        assert_eq!(run("function first(xs) { return Some(xs[0]); } first([4])?;"), Ok(EvaluationResult::Value(Value::Int(4))));
+        assert_eq!(
+            run("Err(\"oops\")?;"),
+            Err("'?' can only be used inside a function (got Err(\"oops\"))".to_string())
+        );
+        assert_eq!(run("1?;"), Err("'?' expects a result or an option, got int".to_string()));
+    }
+
+    // 自分自身を含むリストも、表示と比較で止まる
+    #[test]
+    fn test_cyclic_lists() {
+        assert_eq!(display(run("let xs = [1]; xs.push(xs); xs")), Ok("[1, [...]]".to_string()));
+        assert_eq!(display(run("let xs = [1]; xs.push(Some(xs)); str(xs)")), Ok("[1, Some([...])]".to_string()));
+        let source = "
+            let xs = [1]
+            xs.push(xs)
+            let ys = [1]
+            ys.push(ys)
+            let zs = [2]
+            zs.push(zs);
+            [xs == xs, xs == ys, xs == zs, xs != ys, xs in [xs]]
+        ";
+        assert_eq!(display(run(source)), Ok("[1, 1, 0, 0, 1]".to_string()));
+    }
+
+    // deferはブロックを抜けるときに登録と逆の順で実行される
+    #[test]
+    fn test_defer_runs_in_reverse_order() {
+        let source = "
+            let log = [];
+            if (1) {
+                defer log.push(1);
+                defer log.push(2);
+                log.push(0);
+            }
+            log;
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::list(vec![Value::Int(0), Value::Int(2), Value::Int(1)])))
+        );
+    }
+
+    // 入れ子のブロックからreturnしても、内側から順にdeferが実行される
+    #[test]
+    fn test_defer_on_return_from_nested_block() {
+        let source = "
+            let log = [];
+            function f(n) {
+                defer log.push(\"outer\");
+                while (0 < n) {
+                    const m = n;
+                    defer log.push(m);
+                    if (n < 2) {
+                        defer { log.push(\"inner\"); }
+                        return n;
+                    }
+                    n -= 1;
+                }
+                return 0;
+            }
+            let result = f(2);
+            [result, log];
+        ";
+        let log = Value::list(vec![
+            Value::Int(2),
+            Value::String("inner".to_string()),
+            Value::Int(1),
+            Value::String("outer".to_string()),
+        ]);
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::list(vec![Value::Int(1), log]))));
+    }
+
+    // エラーで抜けるときもdeferは実行され、元のエラーがそのまま伝わる
+    #[test]
+    fn test_defer_on_error() {
+        let source = "
+            let log = [];
+            function f() {
+                defer log.push(1);
+                if (1) {
+                    defer log.push(2);
+                    missing;
+                }
+            }
+            try { f(); } catch (e) { log.push(e.kind()); }
+            log;
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::list(vec![
+                Value::Int(2),
+                Value::Int(1),
+                Value::String("NameError".to_string()),
+            ])))
+        );
+        assert_eq!(run("if (1) { defer missing; 1; }"), Err("Variable 'missing' not found".to_string()));
+        assert_eq!(run("if (1) { defer other; missing; }"), Err("Variable 'missing' not found".to_string()));
+    }
+
+    // クラスの定義、initによる初期化、メソッドからのフィールドの更新
+    #[test]
+    fn test_class_with_constructor_and_methods() {
+        let source = "
+            class Counter {
+                init(start) {
+                    self.n = start
+                }
+                inc(by = 1) {
+                    self.n += by
+                    return self
+                }
+            }
+            let c = Counter(10)
+            c.inc().inc(by: 5)
+            c.n
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(16))));
+        assert_eq!(
+            run("class P { init(x) { self.x = x; self.name = \"p\" } } str(P(1))"),
+            Ok(EvaluationResult::Value(Value::String("P { name: \"p\", x: 1 }".to_string())))
+        );
+        assert_eq!(run("class E {} E(1)"), Err("Class 'E' has no 'init' and takes no arguments".to_string()));
+        assert_eq!(run("class E {} E().missing"), Err("'E' has no field 'missing'".to_string()));
+    }
+
+    // 継承したメソッド、上書き、superによる親のメソッドの呼び出し
+    #[test]
+    fn test_inheritance_and_super() {
+        let source = "
+            class Animal {
+                init(name) { self.name = name }
+                speak() { return [self.name, self.sound()] }
+                sound() { return \"...\" }
+                legs() { return 4 }
+            }
+            class Bird extends Animal {
+                init(name) {
+                    super.init(name)
+                    self.wings = 2
+                }
+                sound() { return \"tweet\" }
+                legs() { return super.legs() - 2 }
+            }
+            class Parrot extends Bird {
+                legs() { return super.legs() }
+            }
+            let p = Parrot(\"polly\");
+            [p.speak(), p.legs(), p.wings]
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::list(vec![
+                Value::list(vec![Value::String("polly".to_string()), Value::String("tweet".to_string())]),
+                Value::Int(2),
+                Value::Int(2),
+            ])))
+        );
+        assert_eq!(run("let Base = 1; class A extends Base {}"), Err("Class 'A' cannot extend int 'Base'".to_string()));
+        assert_eq!(
+            run("class A { f() { return super.f() } } A().f()"),
+            Err("'super' can only be used in a method of a class that extends another class".to_string())
+        );
+    }
+
+    // implで必要なメソッドを確かめ、既定のメソッドを受け継ぐ
+    #[test]
+    fn test_traits_with_default_methods() {
+        let source = "
+            trait Show {
+                show(self);
+                describe(self) { \"<\" + self.show() + \">\" }
+            }
+            class Point {
+                init(x) { self.x = x }
+            }
+            class Label {
+                show() { \"label\" }
+                describe() { \"custom\" }
+            }
+            impl Show for Point {
+                show(self) { \"P\" + str(self.x) }
+            }
+            impl Show for Label {}
+            class Point3 extends Point {}
+            [Point(1).describe(), Label().describe(), implements(Point3(2), Show), implements(1, Show)]
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::list(vec![
+                Value::String("<P1>".to_string()),
+                Value::String("custom".to_string()),
+                Value::Int(1),
+                Value::Int(0),
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_impl_checks_required_methods() {
+        let source = "
+            trait Show { show(self) }
+            class Empty {}
+            impl Show for Empty {}
+        ";
+        assert_eq!(
+            run(source),
+            Err("Class 'Empty' does not implement 'show' required by trait 'Show'".to_string())
+        );
+        assert_eq!(
+            run("class A {} impl A for A {}"),
+            Err("'A' is a class, not a trait".to_string())
+        );
+        assert_eq!(
+            run("trait T {} class A {} impl T for A {} implements(A, T)"),
+            Ok(EvaluationResult::Value(Value::Int(1)))
+        );
+    }
+
+    // 自分自身を含むインスタンスも、表示で止まる
+    #[test]
+    fn test_cyclic_instances() {
+        assert_eq!(display(run("class Node {} let n = Node(); n.next = n; [n]")), Ok("[Node { next: Node {...} }]".to_string()));
+        assert_eq!(display(run("class Node {} let n = Node(); n.items = [n]; n")), Ok("Node { items: [Node {...}] }".to_string()));
+    }
+
+    // ==, !=, <=, >= と文字列の比較
+    #[test]
+    fn test_equality_and_comparison() {
+        let results = run("[1 == 1, 1 != 1, 2 <= 2, 1 >= 2, \"a\" < \"b\", [1, 2] == [1, 2], 1 < 2 == 1, None == None]");
+        assert_eq!(
+            results,
+            Ok(EvaluationResult::Value(Value::list(
+                [1, 0, 1, 0, 1, 1, 1, 1].into_iter().map(Value::Int).collect()
+            )))
+        );
+    }
+
+    // インスタンスは演算子のメソッドを定義して演算子を使える
+    #[test]
+    fn test_operator_overloading() {
+        let source = "
+            class Vec2 {
+                init(x, y) { self.x = x; self.y = y }
+                __add__(other) { Vec2(self.x + other.x, self.y + other.y) }
+                __mul__(k) { Vec2(self.x * k, self.y * k) }
+                __rmul__(k) { self * k }
+                __eq__(other) { self.x == other.x }
+            }
+            let v = Vec2(1, 2) + Vec2(3, 4)
+            v += Vec2(1, 1)
+            let w = 2 * v;
+            [w.x, w.y, Vec2(1, 0) == Vec2(1, 5), Vec2(1, 0) != Vec2(2, 0)]
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::list(
+                [10, 14, 1, 1].into_iter().map(Value::Int).collect()
+            )))
+        );
+    }
+
+    // 比較は向きを入れ替えたメソッドでも解決し、トレイトの既定のメソッドでも定義できる
+    #[test]
+    fn test_comparison_through_traits() {
+        let source = "
+            trait Ordered {
+                __lt__(self, other)
+                __gt__(self, other) { other < self }
+            }
+            class Money {
+                init(cents) { self.cents = cents }
+            }
+            impl Ordered for Money {
+                __lt__(self, other) { self.cents < other.cents }
+            }
+            let a = Money(100)
+            let b = Money(250);
+            [a < b, a > b, b > a, a == a, a == Money(100)]
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::list(
+                [1, 0, 1, 1, 0].into_iter().map(Value::Int).collect()
+            )))
+        );
+        assert_eq!(
+            run("class A {} A() + 1"),
+            Err("Unsupported literal types for binary operation".to_string())
+        );
+    }
+
+    // リストやSomeの中のインスタンスも、__eq__ か同じものかどうかで比べる
+    #[test]
+    fn test_nested_instance_equality() {
+        let source = "
+            class A {}
+            let a = A();
+            [A() == A(), [A()] == [A()], A() in [A()], Some(A()) == Some(A()), [a] == [a], Some(a) == Some(a), a in [1, a], [A()].contains(a)]
+        ";
+        assert_eq!(display(run(source)), Ok("[0, 0, 0, 0, 1, 1, 1, 0]".to_string()));
+        let source = "
+            class A {
+                init(n) { self.n = n }
+                __eq__(other) { 1 }
+            }
+            [A(1) == A(2), [A(1)] == [A(2)], [[A(1)]] != [[A(2)]], Ok(A(1)) == Ok(A(2)), A(1) in [A(2)], [A(2)].contains(A(1)), [A(1)] == [A(2), A(3)]]
+        ";
+        assert_eq!(display(run(source)), Ok("[1, 1, 0, 1, 1, 1, 0]".to_string()));
+    }
+
+    // 無限に続くジェネレーターも、取り出した分だけ実行される
+    #[test]
+    fn test_infinite_generator_is_lazy() {
+        let source = "
+            let calls = 0
+            function* naturals(start = 0) {
+                let n = start
+                while (1) {
+                    calls += 1
+                    yield n
+                    n += 1
+                }
+            }
+            let g = naturals(5)
+            let taken = []
+            while (len(taken) < 3) {
+                taken.push(next(g).unwrap())
+            }
+            let h = naturals();
+            [taken, calls, h.next(), next(g)]
+        ";
+        assert_eq!(
+            display(run(source)),
+            Ok("[[5, 6, 7], 3, Some(0), Some(8)]".to_string())
+        );
+    }
+
+    // for-inはジェネレーターを一つずつ進め、returnで抜ければ残りは実行しない
+    #[test]
+    fn test_for_in_over_generator() {
+        let source = "
+            function fib() {
+                let a = 0
+                let b = 1
+                while (1) {
+                    yield a
+                    const next_b = a + b
+                    a = b
+                    b = next_b
+                }
+            }
+            function first_over(limit) {
+                for (x in fib()) {
+                    if (x > limit) { return x }
+                }
+            }
+            function* evens(xs) {
+                for (x in xs) {
+                    if (x % 2 == 0) { yield x }
+                }
+            }
+            let found = []
+            for (x in evens(1..=6)) { found.push(x) }
+            [first_over(100), found]
+        ";
+        assert_eq!(
+            display(run(source)),
+            Ok("[144, [2, 4, 6]]".to_string())
+        );
+    }
+
+    // ジェネレーターが終わるとNoneを返し続け、本体のdeferはそのときに実行される
+    #[test]
+    fn test_generator_finishes() {
+        let source = "
+            let log = []
+            function* two() {
+                defer log.push(\"done\")
+                yield 1
+                if (1) { return }
+                yield 2
+            }
+            let g = two()
+            log.push(str(next(g)))
+            log.push(str(next(g)))
+            log.push(str(next(g)))
+            log
+        ";
+        assert_eq!(
+            display(run(source)),
+            Ok(r#"["Some(1)", "done", "None", "None"]"#.to_string())
+        );
+        assert_eq!(
+            run("yield 1"),
+            Err("'yield' can only be used inside a generator function".to_string())
+        );
+        assert_eq!(
+            run("function* g() { try { yield 1 } catch (e) {} } next(g())"),
+            Err("'yield' cannot be used inside try/catch/finally".to_string())
+        );
+        // マクロの展開でtryやdeferの中に入ったyieldも同じエラーになる
+        assert_eq!(
+            run("macro guarded(body) { try { body } catch (e) {} }\nfunction* g() { guarded!() { yield 1 } }\nnext(g())"),
+            Err("'yield' cannot be used inside try/catch/finally".to_string())
+        );
+        assert_eq!(
+            run("macro later(body) { defer body }\nfunction* g() { later!() { yield 1 } }\nnext(g())"),
+            Err("'yield' cannot be used inside defer".to_string())
+        );
+    }
+
+    // spawnしたタスクは並行に進み、仮想時計では待ち時間が重なる
+    #[test]
+    fn test_async_tasks_overlap() {
+        let source = "
+            let log = []
+            async function work(name, ms) {
+                log.push(name + \" start\")
+                await sleep(ms)
+                log.push(name + \" end\")
+                return ms
+            }
+            async function main() {
+                let a = spawn(work(\"a\", 100))
+                let b = spawn(work(\"b\", 30))
+                let x = await a
+                let y = await b
+                return [x + y, now()]
+            }
+            async function sequential() {
+                let start = now()
+                await work(\"c\", 10)
+                await work(\"d\", 20)
+                return now() - start
+            }
+            [await main(), await sequential(), log]
+        ";
+        assert_eq!(
+            display(run_with(&mut Evaluator::with_virtual_clock(), source)),
+            Ok(r#"[[130, 100], 30, ["a start", "b start", "b end", "a end", "c start", "c end", "d start", "d end"]]"#.to_string())
+        );
+    }
+
+    // タスクはawaitかspawnまで始まらず、spawnだけしたタスクもプログラムの終わりまでに実行される
+    #[test]
+    fn test_spawned_tasks_finish_before_exit() {
+        let mut evaluator = Evaluator::with_virtual_clock();
+        let source = "
+            let log = []
+            async function note(x) {
+                await sleep(5)
+                log.push(x)
+            }
+            let lazy = note(1)
+            spawn(note(2))
+            log.push(len(log))
+        ";
+        assert_eq!(display(run_with(&mut evaluator, source)), Ok("Unit".to_string()));
+        assert_eq!(display(run_with(&mut evaluator, "[log, now()]")), Ok("[[0, 2], 5]".to_string()));
+    }
+
+    // ホスト関数は保留中の値を返せて、スケジューラーが終わるまでpollする
+    #[test]
+    fn test_host_pending_values() {
+        use crate::interpreter::scheduler::{pending, HostFuture};
+        use std::task::Poll;
+
+        struct Delay {
+            ms: u64,
+            due: Option<u64>,
+            value: Value,
+        }
+        impl HostFuture for Delay {
+            fn poll(&mut self, now: u64) -> Poll<Result<Value, RuntimeError>> {
+                let due = *self.due.get_or_insert(now + self.ms);
+                if now >= due { Poll::Ready(Ok(self.value.clone())) } else { Poll::Pending }
+            }
+        }
+
+        let mut evaluator = Evaluator::with_virtual_clock();
+        evaluator.define_host_function("fetch", |args| match args.as_slice() {
+            [Value::Int(ms), value] => Ok(pending("fetch", Delay { ms: *ms as u64, due: None, value: value.clone() })),
+            _ => Err(RuntimeError::new("TypeError", "fetch(ms, value)")),
+        });
+        let source = "
+            async function both() {
+                let a = spawn(fetch(40, \"x\"))
+                let b = spawn(fetch(40, \"y\"))
+                let x = await a
+                let y = await b
+                return [x, y, now()]
+            }
+            await both()
+        ";
+        assert_eq!(display(run_with(&mut evaluator, source)), Ok(r#"["x", "y", 40]"#.to_string()));
+    }
+
+    #[test]
+    fn test_async_errors() {
+        let mut evaluator = Evaluator::with_virtual_clock();
+        let source = "
+            async function fail() {
+                await sleep(1)
+                throw error(\"IOError\", \"offline\")
+            }
+            async function main() {
+                await fail()
+                return 1
+            }
+            let caught = \"\"
+            try { await main() } catch (e) { caught = e.kind() }
+            caught
+        ";
+        assert_eq!(display(run_with(&mut evaluator, source)), Ok("IOError".to_string()));
+        assert_eq!(
+            run("async function f() { return 1 } function g() { return await f() } g()"),
+            Err("'await' can only be used at the top level or inside an async function".to_string())
+        );
+        assert_eq!(run("await 1"), Err("Cannot await int".to_string()));
+    }
+
+    // awaitされずに失敗したタスクのエラーは、プログラムのエラーとして報告する
+    #[test]
+    fn test_unawaited_task_errors() {
+        let mut evaluator = Evaluator::with_virtual_clock();
+        let source = "async function w() { await sleep(100); undefined_fn() }; spawn(w()); 1";
+        assert_eq!(
+            run_with(&mut evaluator, source),
+            Err("Uncaught error in task 'w': Function 'undefined_fn' not found".to_string())
+        );
+        let mut evaluator = Evaluator::with_virtual_clock();
+        let source = "
+            async function w() { await sleep(100); throw error(\"IOError\", \"offline\") }
+            let t = spawn(w())
+            await sleep(200)
+            let caught = \"\"
+            try { await t } catch (e) { caught = e.kind() }
+            caught
+        ";
+        assert_eq!(display(run_with(&mut evaluator, source)), Ok("IOError".to_string()));
+        let mut evaluator = Evaluator::with_virtual_clock();
+        let source = "
+            async function inner() { throw error(\"IOError\", \"offline\") }
+            async function outer() { await inner() }
+            spawn(outer())
+        ";
+        assert_eq!(run_with(&mut evaluator, source), Err("Uncaught error in task 'outer': offline".to_string()));
+    }
+
+    // 重い計算を複数のスレッドに分けて、joinで結果を集める
+    #[test]
+    fn test_threads_split_work() {
+        let source = "
+            function sum_range(r) {
+                let total = 0
+                for (i in r) { total += i }
+                return total
+            }
+            let threads = []
+            for (k in 0..4) {
+                threads.push(thread_spawn(sum_range, (k * 1000)..((k + 1) * 1000)))
+            }
+            let total = 0
+            for (t in threads) { total += t.join() }
+            total
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::Int(3999 * 4000 / 2))));
+    }
+
+    // 引数は深くコピーされるので、スレッドの中で変更しても呼び出し側には影響しない
+    #[test]
+    fn test_thread_arguments_are_copied() {
+        let source = "
+            function mutate(xs) {
+                xs.push(99)
+                return xs
+            }
+            let xs = [1]
+            let t = thread_spawn(mutate, xs);
+            [join(t), xs]
+        ";
+        assert_eq!(display(run(source)), Ok("[[1, 99], [1]]".to_string()));
+    }
+
+    // 送る側のスレッドが終わればfor-inは止まり、closeしたチャンネルも最後まで読んでから止まる
+    #[test]
+    fn test_channels_shut_down_without_deadlock() {
+        let source = "
+            function produce(out, n) {
+                for (i in 1..=n) { out.send(i * i) }
+            }
+            function echo(inbox, outbox) {
+                for (x in inbox) { outbox.send(x + 1) }
+                close(outbox)
+                return \"echo done\"
+            }
+            let squares = chan(\"int\")
+            let producer = thread_spawn(produce, squares, 4)
+            let got = []
+            for (x in squares) { got.push(x) }
+
+            let inbox = chan()
+            let outbox = chan()
+            let echoer = thread_spawn(echo, inbox, outbox)
+            for (i in 0..3) { inbox.send(i * 10) }
+            close(inbox)
+            let echoed = []
+            for (x in outbox) { echoed.push(x) }
+            [got, echoed, join(producer), join(echoer), recv(chan(), 5)]
+        ";
+        assert_eq!(
+            display(run(source)),
+            Ok(r#"[[1, 4, 9, 16], [1, 11, 21], Unit, "echo done", None]"#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_thread_errors() {
+        let source = "
+            function fail(x) { throw error(\"WorkerError\", \"bad \" + str(x)) }
+            let t = thread_spawn(fail, 7)
+            let caught = \"\"
+            try { join(t) } catch (e) { caught = e.message() }
+            caught
+        ";
+        assert_eq!(display(run(source)), Ok("bad 7".to_string()));
+        assert_eq!(
+            run("class P {} function f(p) { return 1 } thread_spawn(f, P())"),
+            Err("Value of type instance cannot be sent to another thread".to_string())
+        );
+        assert_eq!(
+            run("let c = chan(\"int\"); c.send(\"x\")"),
+            Err("Cannot send string on a channel of int".to_string())
+        );
+        assert_eq!(
+            run("function f() { return 1 } let t = thread_spawn(f); join(t); join(t)"),
+            Err("Thread 'f' has already been joined".to_string())
+        );
+    }
+
+    // importしたモジュールの関数は、そのモジュールのグローバル変数のコピーを使ってスレッドで動く
+    #[test]
+    fn test_threads_copy_module_globals() {
+        let (mut evaluator, _dir) = with_modules("thread-globals", &[
+            ("math.sim", "let counter = 0\npub const step = 2\npub function bump() { counter += step; return counter }\npub function square(x) { return x * x }"),
+            ("util.sim", "import \"math.sim\" as math\nclass Box {}\npub function cube(x) { return x * math.square(x) }\npub function boxed() { return Box }"),
+        ]);
+        let source = "
+            import \"math.sim\" as math
+            import \"util.sim\" as util
+            math.bump()
+            let copied = thread_spawn(math.bump).join();
+            [copied, math.bump(), thread_spawn(util.cube, 3).join()]
+        ";
+        assert_eq!(display(run_with(&mut evaluator, source)), Ok("[4, 4, 27]".to_string()));
+        // クラスはコピーできないので、スレッドからは見えない
+        assert_eq!(
+            run_with(&mut evaluator, "thread_spawn(util.boxed).join()"),
+            Err("Variable 'Box' not found".to_string())
+        );
+        assert_eq!(
+            run("let xs = [1]; xs.push(xs); function f(x) { return 1 } thread_spawn(f, xs)"),
+            Err("A list that contains itself cannot be sent to another thread".to_string())
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "
+            macro swap(a, b) { let t = a; a = b; b = t }
+            macro repeat(n, body) { let i = 0; while (i < n) { body; i += 1 } }
+            let t = 1
+            let u = 2
+            swap!(t, u)
+            let i = 100
+            let log = []
+            repeat!(3) { log.push(i) }
+            [t, u, log]
+        ";
+        assert_eq!(display(run(source)), Ok("[2, 1, [100, 100, 100]]".to_string()));
+        assert_eq!(
+            run("macro m() { let hidden = 1 } m!(); hidden"),
+            Err("Variable 'hidden' not found".to_string())
+        );
+    }
+
+    // 一時ディレクトリを、テストが終わって手放したときに消す
+    struct TempDir(std::path::PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // テスト用のモジュールを一時ディレクトリに書き出し、そこにあるmain.simとして実行する評価器を返す
+    // ディレクトリはTempDirを手放すと消える
+    fn with_modules(test: &str, files: &[(&str, &str)]) -> (Evaluator, TempDir) {
+        let dir = std::env::temp_dir().join(format!("simlang-{}-{}", test, std::process::id()));
+        for (name, source) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, source).unwrap();
+        }
+        let mut evaluator = Evaluator::new();
+        evaluator.set_main_path(dir.join("main.sim"));
+        (evaluator, TempDir(dir))
+    }
+
+    #[test]
+    fn test_import_modules() {
+        let (mut evaluator, _dir) = with_modules("import", &[
+            ("lib/math.sim", "pub const pi = 3\npub function square(x) { return x * x }\npub function area(r) { return pi * square(r) }"),
+            ("lib/util.sim", "import \"math.sim\" as math\npub function clamp(x, lo, hi) { if (x < lo) { return lo } if (x > hi) { return hi } return x }\npub function cube(x) { return x * math.square(x) }"),
+        ]);
+        let source = "
+            import \"lib/math.sim\" as math
+            from \"lib/util.sim\" import clamp, cube
+            let pi = 100
+            function square(x) { return 0 }
+            [math.square(4), math.area(2), math.pi, clamp(15, 0, 10), cube(2), square(4), pi]
+        ";
+        assert_eq!(display(run_with(&mut evaluator, source)), Ok("[16, 12, 3, 10, 8, 0, 100]".to_string()));
+    }
+
+    #[test]
+    fn test_modules_are_evaluated_once() {
+        let (mut evaluator, _dir) = with_modules("once", &[
+            ("shared.sim", "loaded()\npub const items = []"),
+            ("b.sim", "import \"shared.sim\" as shared\nshared.items.push(\"b\")"),
+            ("c.sim", "import \"./shared.sim\" as shared\nshared.items.push(\"c\")"),
+        ]);
+        let loads = Rc::new(std::cell::Cell::new(0));
+        let counter = loads.clone();
+        evaluator.define_host_function("loaded", move |_| {
+            counter.set(counter.get() + 1);
+            Ok(Value::Unit)
+        });
+        let source = "
+            import \"b.sim\" as b
+            import \"c.sim\" as c
+            import \"shared.sim\" as shared
+            shared.items
+        ";
+        assert_eq!(display(run_with(&mut evaluator, source)), Ok(r#"["b", "c"]"#.to_string()));
+        assert_eq!(loads.get(), 1);
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let files = [
+            ("x.sim", "import \"y.sim\" as y"),
+            ("y.sim", "import \"x.sim\" as x"),
+            ("m.sim", "pub function f() { return 1 }"),
+        ];
+        let (mut evaluator, _dir) = with_modules("errors", &files);
+        assert_eq!(
+            run_with(&mut evaluator, "import \"x.sim\" as x"),
+            Err(r#"Cyclic import: "x.sim" -> "y.sim" -> "x.sim""#.to_string())
+        );
+        assert_eq!(
+            run_with(&mut evaluator, "from \"m.sim\" import g"),
+            Err("'g' is not defined in module 'm' (exported: f)".to_string())
+        );
+        assert_eq!(
+            run_with(&mut evaluator, "import \"m.sim\" as m; m.g()"),
+            Err("'g' is not defined in module 'm' (exported: f)".to_string())
+        );
+        let source = "
+            let kind = \"\"
+            try { import \"missing.sim\" as missing } catch (e) { kind = e.kind() }
+            kind
+        ";
+        assert_eq!(display(run_with(&mut evaluator, source)), Ok("ImportError".to_string()));
+    }
+
+    #[test]
+    fn test_module_members_are_private_by_default() {
+        let source = "
+            function helper(x) { return x * 10 }
+            const secret = 42
+            pub const limit = 5
+            pub function scaled(x) { return helper(x) + secret }
+            pub class Point { init(x) { self.x = x } }
+            export trait Shape { area(self) }
+        ";
+        let (mut evaluator, _dir) = with_modules("private", &[("geometry.sim", source)]);
+        let source = "
+            import \"geometry.sim\" as g
+            from \"geometry.sim\" import scaled, Point;
+            [g.limit, scaled(1), Point(3).x, g.Shape]
+        ";
+        assert_eq!(display(run_with(&mut evaluator, source)), Ok("[5, 52, 3, <trait Shape>]".to_string()));
+        assert_eq!(
+            run_with(&mut evaluator, "from \"geometry.sim\" import helper"),
+            Err("'helper' is private to module 'geometry' (exported: Point, Shape, limit, scaled)".to_string())
+        );
+        assert_eq!(
+            run_with(&mut evaluator, "g.secret"),
+            Err("'secret' is private to module 'geometry' (exported: Point, Shape, limit, scaled)".to_string())
+        );
+        assert_eq!(
+            run("function f() { pub const x = 1 } f()"),
+            Err("'pub' can only be used at the top level of a module, not on 'x'".to_string())
+        );
+    }
+
+    // 文字列の添字・スライス・for-in はコードポイント単位で、要素は文字になる
+    #[test]
+    fn test_strings_are_indexed_by_code_point() {
+        assert_eq!(run("\"日本語\"[1];"), Ok(EvaluationResult::Value(Value::Char('本'))));
+        assert_eq!(run("\"日本語\"[1..3];"), Ok(EvaluationResult::Value(Value::String("本語".to_string()))));
+        assert_eq!(run("\"a🐱b\"[2];"), Ok(EvaluationResult::Value(Value::Char('b'))));
+        assert_eq!(run("len(\"日本語\");"), Ok(EvaluationResult::Value(Value::Int(3))));
+        let source = "
+            let codes = [];
+            for (c in \"ねこ🐱\") { codes.push(c.code()); }
+            codes;
+        ";
+        assert_eq!(
+            run(source),
+            Ok(EvaluationResult::Value(Value::list(vec![Value::Int(0x306D), Value::Int(0x3053), Value::Int(0x1F431)])))
+        );
+        assert_eq!(run("'猫' in \"黒猫\";"), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(run("'犬' in \"黒猫\";"), Ok(EvaluationResult::Value(Value::Int(0))));
+    }
+
+    // 文字の値: リテラル、比較、連結、表示
+    #[test]
+    fn test_char_values() {
+        assert_eq!(run("'猫';"), Ok(EvaluationResult::Value(Value::Char('猫'))));
+        assert_eq!(display(run("['a', \"b\", '語'];")), Ok("['a', \"b\", '語']".to_string()));
+        assert_eq!(run("'あ' < 'い';"), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(run("'a' == \"a\";"), Ok(EvaluationResult::Value(Value::Int(0))));
+        assert_eq!(run("\"黒\" + '猫' + '!';"), Ok(EvaluationResult::Value(Value::String("黒猫!".to_string()))));
+        assert_eq!(run("str('é') + \"t\";"), Ok(EvaluationResult::Value(Value::String("ét".to_string()))));
+        assert_eq!(run("char(128049);"), Ok(EvaluationResult::Value(Value::Char('🐱'))));
+        assert_eq!(run("char(55296);"), Err("55296 is not a valid code point".to_string()));
+        assert_eq!(run("'字'.is_alphabetic() + '７'.is_numeric() + '\u{3000}'.is_whitespace();"), Ok(EvaluationResult::Value(Value::Int(3))));
+        assert_eq!(run("'🐱'.byte_len();"), Ok(EvaluationResult::Value(Value::Int(4))));
+        assert_eq!(run(r#"'\t'.code() + len("a\n");"#), Ok(EvaluationResult::Value(Value::Int(11))));
+        assert_eq!(run(r#"str('\'') + "\"\\";"#), Ok(EvaluationResult::Value(Value::String("'\"\\".to_string()))));
+    }
+
+    // コードポイント・バイト・書記素クラスタは別々のメソッドで数える
+    #[test]
+    fn test_string_units() {
+        // 「か」と結合用の濁点(U+3099)で見た目は「が」の一文字
+        let ga = "\"か\u{3099}\"";
+        assert_eq!(run(&format!("{}.len();", ga)), Ok(EvaluationResult::Value(Value::Int(2))));
+        assert_eq!(run(&format!("{}.byte_len();", ga)), Ok(EvaluationResult::Value(Value::Int(6))));
+        assert_eq!(run(&format!("len({}.graphemes());", ga)), Ok(EvaluationResult::Value(Value::Int(1))));
+        // 家族の絵文字はゼロ幅接合子でつないだ5つのコードポイント
+        let family = "\"👨\u{200D}👩\u{200D}👧!\"";
+        assert_eq!(run(&format!("{}.len();", family)), Ok(EvaluationResult::Value(Value::Int(6))));
+        assert_eq!(
+            run(&format!("{}.graphemes();", family)),
+            Ok(EvaluationResult::Value(Value::list(vec![
+                Value::String("👨\u{200D}👩\u{200D}👧".to_string()),
+                Value::String("!".to_string()),
+            ])))
+        );
+        assert_eq!(
+            run("\"é\".bytes();"),
+            Ok(EvaluationResult::Value(Value::list(vec![Value::Int(0xC3), Value::Int(0xA9)])))
+        );
+        assert_eq!(
+            run("\"ねこ\".chars();"),
+            Ok(EvaluationResult::Value(Value::list(vec![Value::Char('ね'), Value::Char('こ')])))
+        );
+    }
+
+    // 識別子にはUnicodeの文字が使える
+    #[test]
+    fn test_unicode_identifiers() {
+        let source = "
+            function 挨拶(名前) { return \"こんにちは、\" + 名前; }
+            let café = \"世界\";
+            挨拶(café);
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::String("こんにちは、世界".to_string()))));
+    }
+
+    // bigintは桁あふれせず、intと混ぜるとbigintになる
+    #[test]
+    fn test_big_integers() {
+        let source = "
+            function factorial(n) {
+                let result = 1n;
+                for (i in 1..=n) { result *= i; }
+                return result;
+            }
+            factorial(30);
+        ";
+        assert_eq!(display(run(source)), Ok("265252859812191058636308480000000".to_string()));
+        assert_eq!(display(run("9223372036854775807n + 1;")), Ok("9223372036854775808".to_string()));
+        assert_eq!(display(run("100000000000000000000 / 3;")), Ok("33333333333333333333".to_string()));
+        assert_eq!(display(run("0 - 2n.pow(100) % 7;")), Ok("-2".to_string()));
+        assert_eq!(display(run("[1n, 2];")), Ok("[1, 2]".to_string()));
+        assert_eq!(run("5n == 5;"), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(display(run("[[5n] == [5], 5 in [5n], Some(5n) != Some(5), [5n].contains(5), [[5]] == [[6n]]];")), Ok("[1, 1, 0, 1, 0]".to_string()));
+        assert_eq!(run("5n < 6 + 100000000000000000000;"), Ok(EvaluationResult::Value(Value::Int(1))));
+        assert_eq!(run("int(5n) + 1;"), Ok(EvaluationResult::Value(Value::Int(6))));
+        assert_eq!(run("int(2n.pow(64));"), Err("18446744073709551616 is too large for int".to_string()));
+        assert_eq!(display(run("bigint(\"123456789012345678901234567890\") + bigint(1);")), Ok("123456789012345678901234567891".to_string()));
+        assert_eq!(run("1n / 0;"), Err("Line 1: Division by zero: 1 / 0".to_string()));
+        assert_eq!(run("1n.pow(2) - 1;"), Ok(EvaluationResult::Value(Value::BigInt(0.into()))));
+    }
+
+    // intの桁あふれはビルドの種類によらずOverflowErrorになる
+    #[test]
+    fn test_checked_int_arithmetic() {
+        assert_eq!(run("9223372036854775807 + 1;"), Err("Integer overflow: 9223372036854775807 + 1".to_string()));
+        assert_eq!(run("(0 - 9223372036854775807) - 2;"), Err("Integer overflow: -9223372036854775807 - 2".to_string()));
+        assert_eq!(run("4294967296 * 4294967296;"), Err("Integer overflow: 4294967296 * 4294967296".to_string()));
+        assert_eq!(run("let m = (0 - 9223372036854775807) - 1; m / (0 - 1);"), Err("Integer overflow: -9223372036854775808 / -1".to_string()));
+        assert_eq!(run("let m = (0 - 9223372036854775807) - 1; m % (0 - 1);"), Ok(EvaluationResult::Value(Value::Int(0))));
+        assert_eq!(run("let m = (0 - 9223372036854775807) - 1; m %= 0 - 1; m;"), Ok(EvaluationResult::Value(Value::Int(0))));
+        assert_eq!(run("let x = 9223372036854775807; x++;"), Err("Integer overflow: 9223372036854775807 + 1".to_string()));
+        assert_eq!(run("2.pow(63);"), Err("Integer overflow: 2.pow(63)".to_string()));
+        assert_eq!(run("2.pow(62);"), Ok(EvaluationResult::Value(Value::Int(4611686018427387904))));
+        assert_eq!(
+            run("let k = \"\"; try { 9223372036854775807 + 1; } catch (e) { k = e.kind(); } k;"),
+            Ok(EvaluationResult::Value(Value::String("OverflowError".to_string())))
+        );
+        // 折り返しが必要なら明示的に書く
+        assert_eq!(run("wrapping_add(9223372036854775807, 1);"), Ok(EvaluationResult::Value(Value::Int(i64::MIN))));
+        assert_eq!(run("wrapping_sub(0 - 9223372036854775807, 2);"), Ok(EvaluationResult::Value(Value::Int(i64::MAX))));
+        assert_eq!(run("wrapping_mul(4294967296, 4294967297);"), Ok(EvaluationResult::Value(Value::Int(4294967296))));
+        assert_eq!(run("wrapping_div((0 - 9223372036854775807) - 1, 0 - 1);"), Ok(EvaluationResult::Value(Value::Int(i64::MIN))));
+        assert_eq!(run("wrapping_div(1, 0);"), Err("Division by zero: wrapping_div(1, 0)".to_string()));
+        // 比較の演算子を直接渡しても落ちずにTypeErrorになる
+        assert_eq!(int_op(1, &Op::Equal, 1).map_err(|e| e.to_string()), Err("'==' is not an arithmetic operator".to_string()));
+        assert_eq!(big_int_op(BigInt::from(1), &Op::In, BigInt::from(1)).map_err(|e| e.kind().to_string()), Err("TypeError".to_string()));
+    }
+
+    // 0での割り算と余りはZeroDivisionErrorになり、演算子の行が分かる
+    #[test]
+    fn test_division_by_zero() {
+        let source = "
+            function average(xs) {
+                let total = 0
+                for (x in xs) { total += x }
+                return total /
+                    len(xs)
+            }
+            average([])
+        ";
+        assert_eq!(run(source), Err("Line 5: Division by zero: 0 / 0".to_string()));
+        assert_eq!(run("let x = 7\nx %= 0"), Err("Line 2: Modulo by zero: 7 % 0".to_string()));
+        assert_eq!(run("10 / 0n;"), Err("Line 1: Division by zero: 10 / 0".to_string()));
+        assert_eq!(run("divmod(7, 0);"), Err("Division by zero: divmod(7, 0)".to_string()));
+        assert_eq!(
+            run("divmod(0 - 7, 2);"),
+            Ok(EvaluationResult::Value(Value::list(vec![Value::Int(-3), Value::Int(-1)])))
+        );
+        // 捕まえられるので、その後も続けて実行できる
+        let source = "
+            let caught = \"\"
+            try { 1 / 0 } catch (e) { caught = e.kind() }
+            caught
+        ";
+        assert_eq!(run(source), Ok(EvaluationResult::Value(Value::String("ZeroDivisionError".to_string()))));
+        let mut evaluator = Evaluator::new();
+        assert!(run_with(&mut evaluator, "let n = 5; n / 0;").is_err());
+        assert_eq!(run_with(&mut evaluator, "n + 1;"), Ok(EvaluationResult::Value(Value::Int(6))));
+        // importしたモジュールの中では、モジュールのパスも付ける
+        let (mut evaluator, _dir) = with_modules("division", &[("lib/stats.sim", "pub function mean(xs) {\n  return 10 / len(xs)\n}")]);
+        assert_eq!(
+            run_with(&mut evaluator, "import \"lib/stats.sim\" as stats\nstats.mean([])"),
+            Err("In module \"lib/stats.sim\", line 2: Division by zero: 10 / 0".to_string())
+        );
+    }
+
+    // 型の注釈は呼び出しの境界で調べ、注釈のない仮引数は何でも受け取る
+    #[test]
+    fn test_type_annotations() {
+        let source = "
+            function add(x: int, y: int = 1) -> int { x + y }
+            function shout(s: string, ...marks: char) -> string {
+                for (m in marks) { s = s + m }
+                return s
+            }
+            function loose(x, y: any) { x }
+            [add(1, 2), add(y: 5, x: 1), shout(\"hi\", '!', '!'), loose(\"a\", [])]
+        ";
+        assert_eq!(display(run(source)), Ok("[3, 6, \"hi!!\", \"a\"]".to_string()));
+        let defs = "function add(x: int, y: int) -> int { return x + y }\n";
+        assert_eq!(
+            run(&format!("{}add(1, \"2\")", defs)),
+            Err("Function 'add' expects parameter 'y' to be int, got string".to_string())
+        );
+        assert_eq!(
+            run(&format!("{}add(1n, 2)", defs)),
+            Err("Function 'add' expects parameter 'x' to be int, got bigint".to_string())
+        );
+        assert_eq!(
+            run("function name(n: int) -> string { n }\nname(1)"),
+            Err("Function 'name' should return string, got int".to_string())
+        );
+        assert_eq!(
+            run("function f(...xs: int) { xs }\nf(1, 2, \"3\")"),
+            Err("Function 'f' expects parameter 'xs' to be int, got string".to_string())
+        );
+        assert_eq!(
+            run("function f(x: integer) { x }\nf(1)"),
+            Err("Unknown type 'integer' in annotation".to_string())
+        );
+        // 既定値も調べる
+        assert_eq!(
+            run("function f(x: int = \"0\") { x }\nf()"),
+            Err("Function 'f' expects parameter 'x' to be int, got string".to_string())
+        );
+    }
+
+    // クラスの名前は子クラスのインスタンスも、トレイトの名前は実装したクラスのインスタンスも受け取る
+    #[test]
+    fn test_class_and_trait_annotations() {
+        let source = "
+            trait Named { name(self) }
+            class Animal { init(n) { self.n = n } }
+            class Cat extends Animal { grow(self, by: int) -> Cat { self.n += by; self } }
+            impl Named for Cat { name(self) { \"cat\" } }
+            class Rock { }
+            function describe(a: Animal, n: Named) -> string { n.name() + str(a.n) }
+        ";
+        assert_eq!(
+            run(&format!("{}describe(Cat(1).grow(2), Cat(0))", source)),
+            Ok(EvaluationResult::Value(Value::String("cat3".to_string())))
+        );
+        assert_eq!(
+            run(&format!("{}describe(Rock(), Cat(0))", source)),
+            Err("Function 'describe' expects parameter 'a' to be Animal, got Rock".to_string())
+        );
+        assert_eq!(
+            run(&format!("{}describe(Animal(1), Animal(2))", source)),
+            Err("Function 'describe' expects parameter 'n' to be Named, got Animal".to_string())
+        );
+        assert_eq!(
+            run(&format!("{}Cat(1).grow(\"2\")", source)),
+            Err("Function 'grow' expects parameter 'by' to be int, got string".to_string())
+        );
+    }
 }
@@ -0,0 +1,74 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use crate::interpreter::context::{Binding, Value};
+use crate::parser::ast::Expr;
+
+// ジェネレーター(async関数の本体もこれで実行する)
+// 本体を再帰で評価するとRustのスタックの上では中断できないので、
+// 制御構造の途中の状態をタスクのスタックとして明示的に持つ
+pub struct Generator {
+    pub name: String,
+    // 関数を定義したモジュール(再開するときにそのスコープに切り替える)
+    pub module: usize,
+    pub state: GeneratorState,
+}
+
+pub enum GeneratorState {
+    // 中断中: 関数のスコープと、再開したときに続ける処理
+    Suspended {
+        scopes: Vec<HashMap<String, Binding>>,
+        tasks: Vec<Task>,
+    },
+    Running,
+    Done,
+}
+
+// 中断できる位置で残っている処理
+pub enum Task {
+    // ブロックの残りの文
+    // ブロックごとにスコープを一つ持ち、抜けるときにdeferした式を実行する
+    Block {
+        statements: std::vec::IntoIter<Expr>,
+        deferred: Vec<Expr>,
+    },
+    While {
+        condition: Expr,
+        body: Expr,
+    },
+    ForIn {
+        variable: String,
+        items: Items,
+        body: Expr,
+    },
+    // awaitで中断している(再開するときに結果を受け取る)
+    Receive(Receiver),
+}
+
+// awaitの結果の受け取り方
+pub enum Receiver {
+    Discard,
+    Declare { name: String, constant: bool },
+    Assign(String),
+    Return,
+}
+
+// for-inで繰り返す要素
+pub enum Items {
+    Values(Box<dyn Iterator<Item = Value>>),
+    Generator(Rc<RefCell<Generator>>),
+}
+
+impl fmt::Debug for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<generator {}>", self.name)
+    }
+}
+
+// ジェネレーターは同じものかどうかで比べる
+impl PartialEq for Generator {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
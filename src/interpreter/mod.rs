@@ -1,2 +1,10 @@
 pub mod evaluator;
 pub mod context;
+pub mod builtins;
+pub mod range;
+pub mod error;
+pub mod generator;
+pub mod scheduler;
+pub mod thread;
+pub mod module;
+pub mod prelude;
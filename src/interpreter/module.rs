@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::interpreter::error::RuntimeError;
+use crate::parser::ast::Expr;
+use crate::parser::lexer::tokenizer;
+use crate::parser::macros::Expander;
+use crate::parser::Parser;
+
+// importで読み込んだモジュール
+// メンバーはContextのモジュールのスコープ(idの番号)にある
+#[derive(Debug, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub id: usize,
+}
+
+// モジュールのファイルを探して読み込み、評価済みのものを覚えておく
+// 評価そのものは評価器が行う
+#[derive(Default)]
+pub struct ModuleLoader {
+    // 正規化したパスから評価済みのモジュール
+    cache: HashMap<PathBuf, Rc<Module>>,
+    // 読み込み中のファイル(importが循環していないかを調べる)
+    loading: Vec<(PathBuf, String)>,
+    // モジュールの番号からファイルのパス(相対パスの起点になる)
+    paths: HashMap<usize, PathBuf>,
+    // importしたモジュールの番号から、importに書いたパス(エラーの位置に使う)
+    labels: HashMap<usize, String>,
+}
+
+impl ModuleLoader {
+    // メインのプログラムのファイルを登録する
+    // ほかのモジュールからメインのファイルをimportすると循環になる
+    pub fn set_main_path(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let label = path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().to_string());
+        self.loading.insert(0, (path.clone(), label));
+        self.paths.insert(0, path);
+    }
+
+    // importしたモジュールのファイルの相対パスは、importしたファイルのディレクトリから解決する
+    // メインのプログラムのファイルが分からなければ(REPLなど)、現在のディレクトリから解決する
+    pub fn resolve(&self, importer: usize, path: &str) -> Result<PathBuf, RuntimeError> {
+        let base = self.paths.get(&importer).and_then(|file| file.parent()).unwrap_or(Path::new("."));
+        base.join(path).canonicalize().map_err(|e| {
+            RuntimeError::new("ImportError", format!("Cannot find module \"{}\": {}", path, e))
+        })
+    }
+
+    pub fn cached(&self, path: &Path) -> Option<Rc<Module>> {
+        self.cache.get(path).cloned()
+    }
+
+    // 読み込みを始める(同じファイルを読み込み中なら循環している)
+    pub fn begin(&mut self, path: &Path, label: &str) -> Result<(), RuntimeError> {
+        if let Some(start) = self.loading.iter().position(|(loading, _)| loading == path) {
+            let cycle: Vec<String> = self.loading[start..].iter()
+                .map(|(_, label)| format!("\"{}\"", label))
+                .chain(std::iter::once(format!("\"{}\"", label)))
+                .collect();
+            return Err(RuntimeError::new("ImportError", format!("Cyclic import: {}", cycle.join(" -> "))));
+        }
+        self.loading.push((path.to_path_buf(), label.to_string()));
+        Ok(())
+    }
+
+    // モジュールのスコープの番号とファイルを結びつける
+    pub fn locate(&mut self, module: usize, path: &Path, label: &str) {
+        self.paths.insert(module, path.to_path_buf());
+        self.labels.insert(module, label.to_string());
+    }
+
+    pub fn label(&self, module: usize) -> Option<&str> {
+        self.labels.get(&module).map(String::as_str)
+    }
+
+    // 読み込みを終える(評価に成功していればキャッシュに入れる)
+    pub fn finish(&mut self, path: &Path, module: Option<Rc<Module>>) {
+        self.loading.pop();
+        if let Some(module) = module {
+            self.cache.insert(path.to_path_buf(), module);
+        }
+    }
+}
+
+// モジュールのファイルを読んで、マクロを展開したプログラムにする
+// マクロはモジュールごとに別で、importしたファイルのマクロは使えない
+pub fn parse_file(path: &Path, label: &str) -> Result<Expr, RuntimeError> {
+    let source = std::fs::read_to_string(path).map_err(|e| {
+        RuntimeError::new("ImportError", format!("Cannot read module \"{}\": {}", label, e))
+    })?;
+    parse_source(&source, label)
+}
+
+pub fn parse_source(source: &str, label: &str) -> Result<Expr, RuntimeError> {
+    let syntax_error = |e: String| RuntimeError::new("SyntaxError", format!("In module \"{}\": {}", label, e));
+    let tokens = tokenizer(source).map_err(syntax_error)?;
+    let mut parser = Parser::new(tokens);
+    let program = parser.parse_tokens().map_err(syntax_error)?;
+    Expander::new().expand_program(program).map_err(syntax_error)
+}
+
+// モジュールの名前はファイル名から拡張子を除いたもの
+pub fn module_name(path: &Path) -> String {
+    path.file_stem().map_or_else(|| path.display().to_string(), |stem| stem.to_string_lossy().to_string())
+}
@@ -0,0 +1,63 @@
+// simlangで書いた標準の関数
+// バイナリに埋め込み、Evaluator::newがモジュールとして読み込む
+// pubを付けた関数は、どのモジュールからも組み込み関数と同じように名前で呼べる
+pub const SOURCE: &str = include_str!("prelude.sim");
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::evaluator::Evaluator;
+    use crate::interpreter::evaluator::tests::{display, run, run_with};
+
+    #[test]
+    fn test_min_max_abs() {
+        assert_eq!(display(run("[min(3, 1, 2), max(3, 1, 2), min(7), abs(0 - 5), abs(4)]")), Ok("[1, 3, 7, 5, 4]".to_string()));
+    }
+
+    #[test]
+    fn test_range() {
+        assert_eq!(display(run("[range(3).to_list(), range(2, 5).to_list(), range(0, 10, 3).to_list()]")), Ok("[[0, 1, 2], [2, 3, 4], [0, 3, 6, 9]]".to_string()));
+    }
+
+    #[test]
+    fn test_map_filter_reduce() {
+        let source = "
+            function double(x) { return x * 2 }
+            function odd(x) { return x % 2 }
+            function add(a, b) { return a + b }
+            [map([1, 2, 3], double), filter(1..=6, odd), reduce([1, 2, 3, 4], add), reduce([], add, 10), [1, 2, 3].map(double) |> reduce(add)]
+        ";
+        assert_eq!(display(run(source)), Ok("[[2, 4, 6], [1, 3, 5], 10, 10, 12]".to_string()));
+        assert_eq!(
+            run("function add(a, b) { return a + b }\nreduce([], add)"),
+            Err("reduce() of an empty sequence with no initial value".to_string())
+        );
+    }
+
+    #[test]
+    fn test_assert() {
+        assert_eq!(display(run("assert(1 + 1 == 2)")), Ok("Unit".to_string()));
+        assert_eq!(display(run("assert(1 == 2, \"math is broken\")")), Err("math is broken".to_string()));
+        let source = "
+            let kind = \"\"
+            try { assert(0) } catch (e) { kind = e.kind() + \": \" + e.message() }
+            kind
+        ";
+        assert_eq!(display(run(source)), Ok("AssertionError: Assertion failed".to_string()));
+    }
+
+    // スクリプトで定義した同名の関数がプレリュードより優先される
+    #[test]
+    fn test_user_definitions_shadow_the_prelude() {
+        let source = "
+            function max(a, b) { return 0 }
+            function min(a, b) { return 99 }
+            [max(1, 2), map([5, 1], abs)]
+        ";
+        assert_eq!(display(run(source)), Ok("[0, [5, 1]]".to_string()));
+    }
+
+    #[test]
+    fn test_prelude_can_be_disabled() {
+        assert_eq!(display(run_with(&mut Evaluator::without_prelude(), "max(1, 2)")), Err("Function 'max' not found".to_string()));
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+
+// 整数の範囲 a..b / a..=b
+// 要素はリストにせず、必要なときに計算する
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IntRange {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+    pub inclusive: bool,
+}
+
+impl IntRange {
+    pub fn new(start: i64, end: i64, inclusive: bool) -> Self {
+        IntRange { start, end, step: 1, inclusive }
+    }
+
+    pub fn with_step(self, step: i64) -> Result<Self, String> {
+        if step == 0 {
+            return Err("Range step cannot be zero".to_string());
+        }
+        Ok(IntRange { step, ..self })
+    }
+
+    // 要素数(オーバーフローしないようにi128で計算する)
+    pub fn len(&self) -> usize {
+        let (start, end, step) = (self.start as i128, self.end as i128, self.step as i128);
+        // 終端を含まない形にそろえる
+        let end = match (self.inclusive, step > 0) {
+            (false, _) => end,
+            (true, true) => end + 1,
+            (true, false) => end - 1,
+        };
+        let span = if step > 0 { end - start } else { start - end };
+        if span <= 0 {
+            0
+        } else {
+            let step = step.abs();
+            ((span + step - 1) / step).min(usize::MAX as i128) as usize
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<i64> {
+        if index < self.len() {
+            Some((self.start as i128 + index as i128 * self.step as i128) as i64)
+        } else {
+            None
+        }
+    }
+
+    // 要素数はusizeに収まらないことがあるので、両端と比べる
+    pub fn contains(&self, value: i64) -> bool {
+        let (value, start, end, step) = (value as i128, self.start as i128, self.end as i128, self.step as i128);
+        let within = match (step > 0, self.inclusive) {
+            (true, false) => start <= value && value < end,
+            (true, true) => start <= value && value <= end,
+            (false, false) => end < value && value <= start,
+            (false, true) => end <= value && value <= start,
+        };
+        within && (value - start) % step == 0
+    }
+
+    // 添字 from..to の部分範囲(範囲外は切り詰める)
+    pub fn slice(&self, from: usize, to: usize) -> Self {
+        let to = to.min(self.len());
+        let from = from.min(to);
+        let start = self.start as i128 + from as i128 * self.step as i128;
+        let end = self.start as i128 + to as i128 * self.step as i128;
+        IntRange {
+            start: start as i64,
+            end: end as i64,
+            step: self.step,
+            inclusive: false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..self.len()).filter_map(move |i| self.get(i))
+    }
+}
+
+impl fmt::Display for IntRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = if self.inclusive { "..=" } else { ".." };
+        if self.step == 1 {
+            write!(f, "{}{}{}", self.start, op, self.end)
+        } else {
+            write!(f, "({}{}{}).step({})", self.start, op, self.end, self.step)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_len() {
+        assert_eq!(IntRange::new(0, 10, false).len(), 10);
+        assert_eq!(IntRange::new(0, 10, true).len(), 11);
+        assert_eq!(IntRange::new(5, 1, false).len(), 0);
+        assert_eq!(IntRange::new(0, 10, false).with_step(3).unwrap().len(), 4);
+        assert_eq!(IntRange::new(10, 0, false).with_step(-2).unwrap().len(), 5);
+        assert_eq!(IntRange::new(i64::MIN, i64::MAX, true).len(), usize::MAX);
+    }
+
+    #[test]
+    fn test_get_and_contains() {
+        let r = IntRange::new(1, 10, true).with_step(3).unwrap();
+        assert_eq!(r.iter().collect::<Vec<_>>(), vec![1, 4, 7, 10]);
+        assert_eq!(r.get(2), Some(7));
+        assert_eq!(r.get(4), None);
+        assert!(r.contains(10));
+        assert!(!r.contains(5));
+        assert!(!r.contains(13));
+        let down = IntRange::new(10, 0, false).with_step(-5).unwrap();
+        assert!(down.contains(5));
+        assert!(!down.contains(0));
+        assert!(!down.contains(15));
+        let full = IntRange::new(i64::MIN, i64::MAX, true);
+        assert!(full.contains(i64::MAX));
+        assert!(full.contains(i64::MIN));
+        assert!(!IntRange::new(i64::MIN, i64::MAX, false).contains(i64::MAX));
+    }
+
+    #[test]
+    fn test_slice() {
+        let r = IntRange::new(10, 20, false).with_step(2).unwrap();
+        assert_eq!(r.slice(1, 3).iter().collect::<Vec<_>>(), vec![12, 14]);
+        assert_eq!(r.slice(3, 100).iter().collect::<Vec<_>>(), vec![16, 18]);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(IntRange::new(1, 5, false).to_string(), "1..5");
+        assert_eq!(IntRange::new(1, 5, true).with_step(2).unwrap().to_string(), "(1..=5).step(2)");
+    }
+}
@@ -0,0 +1,284 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use crate::interpreter::context::Value;
+use crate::interpreter::error::RuntimeError;
+use crate::interpreter::generator::Generator;
+
+// 非同期タスク
+// async関数の呼び出し、sleep、ホスト関数が返す保留中の値を同じようにawaitできるようにする
+// タスクはawaitかspawnで開始するまで動かない
+pub struct AsyncTask {
+    pub name: String,
+    pub kind: TaskKind,
+    pub state: TaskState,
+    // このタスクが終わるのを待っているタスク
+    pub waiters: Vec<Rc<RefCell<AsyncTask>>>,
+    // 一度でもawaitされたか(awaitされずに失敗したタスクは捕まえられなかったエラーとして報告する)
+    pub awaited: bool,
+}
+
+pub enum TaskKind {
+    // async関数の本体(awaitで中断するジェネレーターとして実行する)
+    Coroutine(Rc<RefCell<Generator>>),
+    // 開始してから指定のミリ秒が経つと終わる
+    Sleep(u64),
+    Host(Box<dyn HostFuture>),
+}
+
+pub enum TaskState {
+    Idle,
+    Pending,
+    Done(TaskResult),
+}
+
+pub type TaskResult = Result<Value, RuntimeError>;
+
+// ホスト関数が返す保留中の値
+// スケジューラーが時計の時刻(ミリ秒)を渡して、終わるまで繰り返しpollする
+pub trait HostFuture {
+    fn poll(&mut self, now: u64) -> Poll<TaskResult>;
+}
+
+impl AsyncTask {
+    pub fn new(name: &str, kind: TaskKind) -> Rc<RefCell<AsyncTask>> {
+        Rc::new(RefCell::new(AsyncTask {
+            name: name.to_string(),
+            kind,
+            state: TaskState::Idle,
+            waiters: Vec::new(),
+            awaited: false,
+        }))
+    }
+
+    // 終わっていれば結果を返す
+    pub fn result(&self) -> Option<TaskResult> {
+        match &self.state {
+            TaskState::Done(result) => Some(result.clone()),
+            _ => None,
+        }
+    }
+}
+
+// ホスト関数から返す保留中の値を作る
+pub fn pending(name: &str, future: impl HostFuture + 'static) -> Value {
+    Value::Task(AsyncTask::new(name, TaskKind::Host(Box::new(future))))
+}
+
+impl fmt::Debug for AsyncTask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<task {}>", self.name)
+    }
+}
+
+// タスクは同じものかどうかで比べる
+impl PartialEq for AsyncTask {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// 仮想時計は待つ代わりに時刻を進めるので、テストの結果が実際の時間に左右されない
+enum Clock {
+    Virtual(u64),
+    Real(Instant),
+}
+
+// 単一スレッドのイベントループの状態
+// コルーチンの再開は評価器が行い、ここでは待ち行列と時計を管理する
+pub struct Scheduler {
+    clock: Clock,
+    // 再開できるコルーチンと、awaitしていた値の結果
+    pub ready: VecDeque<(Rc<RefCell<AsyncTask>>, Option<TaskResult>)>,
+    // (終わる時刻, sleepのタスク)
+    timers: Vec<(u64, Rc<RefCell<AsyncTask>>)>,
+    hosts: Vec<Rc<RefCell<AsyncTask>>>,
+    // 失敗して終わったタスク
+    failed: Vec<Rc<RefCell<AsyncTask>>>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::with_clock(Clock::Real(Instant::now()))
+    }
+
+    pub fn with_virtual_clock() -> Self {
+        Scheduler::with_clock(Clock::Virtual(0))
+    }
+
+    fn with_clock(clock: Clock) -> Self {
+        Scheduler {
+            clock,
+            ready: VecDeque::new(),
+            timers: Vec::new(),
+            hosts: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+
+    // 始まってからのミリ秒
+    pub fn now(&self) -> u64 {
+        match &self.clock {
+            Clock::Virtual(now) => *now,
+            Clock::Real(start) => start.elapsed().as_millis() as u64,
+        }
+    }
+
+    // タスクを開始する(開始済みなら何もしない)
+    pub fn start(&mut self, task: &Rc<RefCell<AsyncTask>>) {
+        let mut inner = task.borrow_mut();
+        if !matches!(inner.state, TaskState::Idle) {
+            return;
+        }
+        inner.state = TaskState::Pending;
+        match &inner.kind {
+            TaskKind::Coroutine(_) => self.ready.push_back((task.clone(), None)),
+            TaskKind::Sleep(ms) => self.timers.push((self.now() + ms, task.clone())),
+            TaskKind::Host(_) => self.hosts.push(task.clone()),
+        }
+    }
+
+    // タスクを終わらせて、待っていたタスクを再開できるようにする
+    pub fn complete(&mut self, task: &Rc<RefCell<AsyncTask>>, result: TaskResult) {
+        let waiters = {
+            let mut inner = task.borrow_mut();
+            inner.state = TaskState::Done(result.clone());
+            std::mem::take(&mut inner.waiters)
+        };
+        if result.is_err() {
+            self.failed.push(task.clone());
+        }
+        for waiter in waiters {
+            self.ready.push_back((waiter, Some(result.clone())));
+        }
+    }
+
+    // 失敗したまま一度もawaitされなかったタスクのエラーを返す(最初の1つだけ)
+    // 失敗したタスクの記録はここで空にする
+    pub fn take_unawaited_error(&mut self) -> Option<RuntimeError> {
+        std::mem::take(&mut self.failed).into_iter().find_map(|task| {
+            let task = task.borrow();
+            match &task.state {
+                TaskState::Done(Err(error)) if !task.awaited => {
+                    Some(error.clone().context(format!("Uncaught error in task '{}'", task.name)))
+                },
+                _ => None,
+            }
+        })
+    }
+
+    // ホストの値をpollし、時刻が来たsleepを終わらせる
+    // 何か終わればtrueを返す
+    pub fn poll_external(&mut self) -> bool {
+        let now = self.now();
+        let mut progressed = false;
+
+        for task in std::mem::take(&mut self.hosts) {
+            let poll = match &mut task.borrow_mut().kind {
+                TaskKind::Host(future) => future.poll(now),
+                _ => Poll::Pending,
+            };
+            match poll {
+                Poll::Ready(result) => {
+                    self.complete(&task, result);
+                    progressed = true;
+                },
+                Poll::Pending => self.hosts.push(task),
+            }
+        }
+
+        // 同じ時刻に終わるsleepは開始した順に終わらせる
+        self.timers.sort_by_key(|(due, _)| *due);
+        while self.timers.first().is_some_and(|(due, _)| *due <= now) {
+            let (_, task) = self.timers.remove(0);
+            self.complete(&task, Ok(Value::Unit));
+            progressed = true;
+        }
+        progressed
+    }
+
+    // 次にsleepが終わるかホストの値を確かめる時刻まで待つ(仮想時計なら時刻を進める)
+    // 待っているものがなければfalseを返す
+    pub fn wait(&mut self) -> bool {
+        let now = self.now();
+        let next_timer = self.timers.iter().map(|(due, _)| *due).min();
+        let target = match (next_timer, self.hosts.is_empty()) {
+            (None, true) => return false,
+            (Some(due), true) => due,
+            // ホストの値は1ミリ秒ごとに確かめる
+            (next_timer, false) => next_timer.map_or(now + 1, |due| due.min(now + 1)),
+        };
+        match &mut self.clock {
+            Clock::Virtual(now) => *now = (*now).max(target),
+            Clock::Real(_) => {
+                if target > now {
+                    std::thread::sleep(Duration::from_millis(target - now));
+                }
+            },
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_clock_jumps_to_timers() {
+        let mut scheduler = Scheduler::with_virtual_clock();
+        let long = AsyncTask::new("sleep", TaskKind::Sleep(50));
+        let short = AsyncTask::new("sleep", TaskKind::Sleep(20));
+        scheduler.start(&long);
+        scheduler.start(&short);
+
+        assert!(!scheduler.poll_external());
+        assert!(scheduler.wait());
+        assert_eq!(scheduler.now(), 20);
+        assert!(scheduler.poll_external());
+        assert_eq!(short.borrow().result(), Some(Ok(Value::Unit)));
+        assert_eq!(long.borrow().result(), None);
+
+        assert!(scheduler.wait());
+        assert!(scheduler.poll_external());
+        assert_eq!(scheduler.now(), 50);
+        assert!(!scheduler.wait());
+    }
+
+    // 3回目のpollで終わるホストの値
+    struct Countdown(u32);
+
+    impl HostFuture for Countdown {
+        fn poll(&mut self, _now: u64) -> Poll<TaskResult> {
+            self.0 -= 1;
+            if self.0 == 0 { Poll::Ready(Ok(Value::Int(7))) } else { Poll::Pending }
+        }
+    }
+
+    #[test]
+    fn test_host_values_are_polled() {
+        let mut scheduler = Scheduler::with_virtual_clock();
+        let task = match pending("countdown", Countdown(3)) {
+            Value::Task(task) => task,
+            other => panic!("expected a task, got {}", other),
+        };
+        scheduler.start(&task);
+        let mut polls = 0;
+        while !scheduler.poll_external() {
+            assert!(scheduler.wait());
+            polls += 1;
+        }
+        assert_eq!(polls, 2);
+        assert_eq!(scheduler.now(), 2);
+        assert_eq!(task.borrow().result(), Some(Ok(Value::Int(7))));
+    }
+}
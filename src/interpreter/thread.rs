@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::interpreter::context::{Function, Value};
+use crate::interpreter::error::RuntimeError;
+use crate::interpreter::module::Module;
+use crate::interpreter::range::IntRange;
+use num_bigint::BigInt;
+
+// スレッドをまたいで渡せる値
+// 評価器の値はRcで共有されるので、そのまま別のスレッドには渡せない
+// 渡すときにこの形へ深くコピーし、受け取った側で新しい値に戻す
+//
+// 渡せるのは int, bigint, string, char, list, range, 関数, 組み込み関数, error, Ok/Err/Some/None, Unit,
+// モジュールとチャンネルだけ(チャンネルは中身がスレッド間で共有される)
+// クラス・インスタンス・ジェネレーター・タスク・スレッドと、自分自身を含むリストは渡せない
+//
+// 関数を渡すと、受け取ったスレッドではその関数を定義したモジュールのスコープで実行される
+// thread_spawnはimportしたモジュールのグローバル変数のうち渡せるものをコピーするので(SendModule)、
+// モジュールの関数はそのモジュールの変数や定数を使える(変更しても元のスレッドには反映されない)
+// 渡せないグローバル変数(クラスなど)とメインのプログラムのグローバル変数はコピーしない
+pub enum SendValue {
+    Int(i64),
+    BigInt(BigInt),
+    String(String),
+    Char(char),
+    List(Vec<SendValue>),
+    Range(IntRange),
+    Function(Box<Function>),
+    Builtin(String),
+    Error { kind: String, message: String },
+    Ok(Box<SendValue>),
+    Err(Box<SendValue>),
+    Some(Box<SendValue>),
+    None,
+    Channel(Arc<Channel>),
+    Module { name: String, id: usize },
+    Unit,
+}
+
+// スレッドに引き継ぐimportしたモジュールの中身
+// 関数はほかのモジュールの関数とまとめて引き継ぐ
+pub struct SendModule {
+    pub id: usize,
+    // (名前, 値, constかどうか)
+    pub globals: Vec<(String, SendValue, bool)>,
+    pub exports: Vec<String>,
+}
+
+impl SendValue {
+    pub fn from_value(value: &Value) -> Result<SendValue, RuntimeError> {
+        SendValue::from_value_nested(value, &mut Vec::new())
+    }
+
+    // copyingはコピーしている途中のリスト(循環していればエラーにする)
+    fn from_value_nested(value: &Value, copying: &mut Vec<*const ()>) -> Result<SendValue, RuntimeError> {
+        let mut wrap = |inner: &Value| SendValue::from_value_nested(inner, copying).map(Box::new);
+        Ok(match value {
+            Value::Int(i) => SendValue::Int(*i),
+            Value::BigInt(i) => SendValue::BigInt(i.clone()),
+            Value::String(s) => SendValue::String(s.clone()),
+            Value::Char(c) => SendValue::Char(*c),
+            Value::List(items) => {
+                let ptr = Rc::as_ptr(items) as *const ();
+                if copying.contains(&ptr) {
+                    return Err(RuntimeError::new("TypeError", "A list that contains itself cannot be sent to another thread"));
+                }
+                copying.push(ptr);
+                let items = items.borrow().iter().map(|item| SendValue::from_value_nested(item, copying)).collect::<Result<_, _>>();
+                copying.pop();
+                SendValue::List(items?)
+            },
+            Value::Range(range) => SendValue::Range(*range),
+            Value::Function(function) => SendValue::Function(Box::new(Function::clone(function))),
+            Value::Builtin(name) => SendValue::Builtin(name.clone()),
+            Value::Error { kind, message } => SendValue::Error { kind: kind.clone(), message: message.clone() },
+            Value::Ok(inner) => SendValue::Ok(wrap(inner)?),
+            Value::Err(inner) => SendValue::Err(wrap(inner)?),
+            Value::Some(inner) => SendValue::Some(wrap(inner)?),
+            Value::None => SendValue::None,
+            Value::Channel(end) => SendValue::Channel(end.channel.clone()),
+            Value::Module(module) => SendValue::Module { name: module.name.clone(), id: module.id },
+            Value::Unit => SendValue::Unit,
+            other => {
+                return Err(RuntimeError::new("TypeError", format!("Value of type {} cannot be sent to another thread", other.type_name())));
+            },
+        })
+    }
+
+    pub fn into_value(self) -> Value {
+        match self {
+            SendValue::Int(i) => Value::Int(i),
+            SendValue::BigInt(i) => Value::BigInt(i),
+            SendValue::String(s) => Value::String(s),
+            SendValue::Char(c) => Value::Char(c),
+            SendValue::List(items) => Value::list(items.into_iter().map(SendValue::into_value).collect()),
+            SendValue::Range(range) => Value::Range(range),
+            SendValue::Function(function) => Value::Function(Rc::new(*function)),
+            SendValue::Builtin(name) => Value::Builtin(name),
+            SendValue::Error { kind, message } => Value::Error { kind, message },
+            SendValue::Ok(inner) => Value::Ok(Box::new(inner.into_value())),
+            SendValue::Err(inner) => Value::Err(Box::new(inner.into_value())),
+            SendValue::Some(inner) => Value::Some(Box::new(inner.into_value())),
+            SendValue::None => Value::None,
+            SendValue::Channel(channel) => Value::Channel(Rc::new(ChannelEnd { channel })),
+            SendValue::Module { name, id } => Value::Module(Rc::new(Module { name, id })),
+            SendValue::Unit => Value::Unit,
+        }
+    }
+}
+
+// スレッド間で値を受け渡すキュー
+// elementがあれば、その型の値しか送れない
+pub struct Channel {
+    pub element: Option<String>,
+    state: Mutex<ChannelState>,
+    ready: Condvar,
+}
+
+struct ChannelState {
+    items: VecDeque<SendValue>,
+    closed: bool,
+}
+
+// スレッドごとのチャンネルの参照
+// 同じスレッドの中でコピーしてもArcの参照数は増えないので、
+// 参照数からほかのスレッドがまだチャンネルを持っているかが分かる
+pub struct ChannelEnd {
+    pub channel: Arc<Channel>,
+}
+
+// recvで待つときに、ほかのスレッドが終わっていないか確かめる間隔
+const RECHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+// 新しいチャンネルを作る
+pub fn channel(element: Option<String>) -> Value {
+    let channel = Channel {
+        element,
+        state: Mutex::new(ChannelState { items: VecDeque::new(), closed: false }),
+        ready: Condvar::new(),
+    };
+    Value::Channel(Rc::new(ChannelEnd { channel: Arc::new(channel) }))
+}
+
+impl Channel {
+
+    pub fn send(&self, value: &Value) -> Result<(), RuntimeError> {
+        if let Some(element) = &self.element {
+            if value.type_name() != element {
+                return Err(RuntimeError::new("TypeError", format!("Cannot send {} on a channel of {}", value.type_name(), element)));
+            }
+        }
+        let value = SendValue::from_value(value)?;
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(RuntimeError::new("ValueError", "Cannot send on a closed channel"));
+        }
+        state.items.push_back(value);
+        self.ready.notify_all();
+        Ok(())
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+impl ChannelEnd {
+    // 値が届くまで待って受け取る
+    // 閉じられて空になったか、ほかに持っているスレッドがいなくなったか、timeoutが過ぎたらNoneを返す
+    pub fn recv(&self, timeout: Option<Duration>) -> Option<Value> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.channel.state.lock().unwrap();
+        loop {
+            if let Some(value) = state.items.pop_front() {
+                return Some(value.into_value());
+            }
+            // 参照が自分だけなら、もう誰も送れない
+            if state.closed || Arc::strong_count(&self.channel) == 1 {
+                return None;
+            }
+            let wait = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) => left.min(RECHECK_INTERVAL),
+                    None => return None,
+                },
+                None => RECHECK_INTERVAL,
+            };
+            state = self.channel.ready.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+}
+
+// thread_spawnが返すハンドル
+// joinは一度だけできる
+pub struct ThreadHandle {
+    pub name: String,
+    pub handle: Option<JoinHandle<Result<SendValue, SendValue>>>,
+}
+
+impl ThreadHandle {
+    // スレッドが終わるのを待って結果を返す
+    // スレッドの中で起きたエラーはここで投げ直す
+    pub fn join(&mut self) -> Result<Value, RuntimeError> {
+        let handle = self.handle.take().ok_or_else(|| {
+            RuntimeError::new("ValueError", format!("Thread '{}' has already been joined", self.name))
+        })?;
+        match handle.join() {
+            Ok(Ok(value)) => Ok(value.into_value()),
+            Ok(Err(error)) => Err(RuntimeError::thrown(error.into_value())),
+            Err(_) => Err(RuntimeError::new("RuntimeError", format!("Thread '{}' panicked", self.name))),
+        }
+    }
+}
+
+// 同じものかどうかで比べる
+impl PartialEq for ChannelEnd {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.channel, &other.channel)
+    }
+}
+
+impl PartialEq for ThreadHandle {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for ChannelEnd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.channel.element {
+            Some(element) => write!(f, "<channel {}>", element),
+            None => write!(f, "<channel>"),
+        }
+    }
+}
+
+impl fmt::Debug for ThreadHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<thread {}>", self.name)
+    }
+}
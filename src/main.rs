@@ -1,45 +1,69 @@
 use simlang::parser::lexer::tokenizer;
 use simlang::parser::Parser;
+use simlang::parser::macros::Expander;
 use simlang::interpreter::evaluator::{Evaluator, EvaluationResult};
-use simlang::parser::ast;
 use std::env;
+use std::process;
 mod repl;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // --expand-macrosを付けると、マクロを展開したASTを表示する
+    let expand_macros = args.iter().any(|arg| arg == "--expand-macros");
+    // --no-preludeを付けると、プレリュードを読み込まない
+    let prelude = !args.iter().any(|arg| arg == "--no-prelude");
+    args.retain(|arg| arg != "--expand-macros" && arg != "--no-prelude");
 
     if args.len() == 1 {
-        repl::run_repl();
+        repl::run_repl(expand_macros, prelude);
     } else {
         let file_name = &args[1];
         let source_code = std::fs::read_to_string(file_name)
             .expect("Failed to read the source file.");
         //println!("Source code: {}", source_code);
-        let (_, tokens) = tokenizer(&source_code)
-            .expect("Failed to tokenize the source code.");
+        let tokens = match tokenizer(&source_code) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                println!("Failed to tokenize the source code: {}", e);
+                process::exit(1);
+            },
+        };
 
-        let mut parser = Parser { tokens, current: 0 };
+        let mut parser = Parser::new(tokens);
 
         let ast = match parser.parse_tokens() {
             Ok(ast) => ast,
             Err(e) => {
                 println!("Failed to parse tokens: {}", e);
-                return;
+                process::exit(1);
+            },
+        };
+
+        let mut expander = Expander::new();
+        expander.debug = expand_macros;
+        let ast = match expander.expand_program(ast) {
+            Ok(ast) => ast,
+            Err(e) => {
+                println!("Failed to expand macros: {}", e);
+                process::exit(1);
             },
         };
 
         //println!("AST: {:?}", ast);
 
-        let mut evaluator = Evaluator::new();
-        let result = evaluator.evaluate(ast)
-            .expect("Failed to evaluate the AST.");
+        let mut evaluator = if prelude { Evaluator::new() } else { Evaluator::without_prelude() };
+        evaluator.set_main_path(file_name);
+        // 捕まえられなかったエラーで終わったら、0以外の終了コードを返す
+        let result = match evaluator.evaluate_program(ast) {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            },
+        };
 
         match result {
-            EvaluationResult::Value(val) | EvaluationResult::ReturnValue(val) => match val {
-                ast::Literal::Int(i) => println!("{}", i),
-                ast::Literal::String(s) => println!("{}", s),
-                _ => println!("{:?}", val),
-            },        
+            EvaluationResult::Value(val) | EvaluationResult::ReturnValue(val) => println!("{}", val),
         }
     }
 }
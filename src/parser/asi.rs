@@ -0,0 +1,192 @@
+// 改行による文の区切り(自動セミコロン挿入)
+//
+// 規則:
+// - 文は `;` か改行で終わる
+// - ただし次の場合は改行を無視して行を続ける
+//   - `(` や `[` の中
+//   - 行末が演算子、`,`、`.`、開き括弧などで終わっている
+//   - 次の行が `.`、`|>`、`else`、`catch`、`finally`、`{` で始まっている
+// - `a` の次の行が `(` や `[` で始まる場合は、呼び出しなのか別の文なのか
+//   区別できないのでエラーにする
+use crate::parser::token::Token;
+
+// 改行を取り除いた字句と、それぞれの字句がある行の番号(first_lineから)を返す
+pub fn insert_semicolons(tokens: Vec<Token>, first_line: usize) -> Result<(Vec<Token>, Vec<usize>), String> {
+    let mut output: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut lines: Vec<usize> = Vec::with_capacity(tokens.len());
+    // 開いている括弧のスタック
+    let mut brackets: Vec<Token> = Vec::new();
+    let mut line = first_line;
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Newline => {
+                // 挿入する `;` は改行の前の行に置く
+                let end_of_line = line;
+                line += 1;
+                if matches!(brackets.last(), Some(Token::LParen) | Some(Token::LBracket)) {
+                    continue;
+                }
+                // 続く改行はまとめて読み飛ばす
+                while tokens.peek() == Some(&Token::Newline) {
+                    tokens.next();
+                    line += 1;
+                }
+                let previous = match output.last() {
+                    Some(previous) if !continues_after(previous) => previous,
+                    _ => continue,
+                };
+                match tokens.peek() {
+                    Some(next) if continues_before(next) => {},
+                    Some(next @ (Token::LParen | Token::LBracket)) if ends_expression(previous) => {
+                        return Err(format!(
+                            "Ambiguous line break before {:?} on line {}: end the previous line with ';' or join the lines",
+                            next, line
+                        ));
+                    },
+                    _ => {
+                        output.push(Token::Semicolon);
+                        lines.push(end_of_line);
+                    },
+                }
+                continue;
+            },
+            Token::LParen | Token::LBracket | Token::LBrace => brackets.push(token.clone()),
+            Token::RParen | Token::RBracket | Token::RBrace => {
+                brackets.pop();
+            },
+            _ => {},
+        }
+        output.push(token);
+        lines.push(line);
+    }
+
+    Ok((output, lines))
+}
+
+// この字句で行が終わっていれば、次の行に続く
+fn continues_after(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Assignment
+            | Token::PlusAssign
+            | Token::MinusAssign
+            | Token::StarAssign
+            | Token::SlashAssign
+            | Token::ModuloAssign
+            | Token::Plus
+            | Token::Minus
+            | Token::Star
+            | Token::Slash
+            | Token::Modulo
+            | Token::LessThan
+            | Token::GreaterThan
+            | Token::LessThanEqual
+            | Token::GreaterThanEqual
+            | Token::DoubleEqual
+            | Token::NotEqual
+            | Token::Pipe
+            | Token::Arrow
+            | Token::LParen
+            | Token::LBrace
+            | Token::LBracket
+            | Token::Colon
+            | Token::Ellipsis
+            | Token::DotDot
+            | Token::DotDotEqual
+            | Token::Dot
+            | Token::Semicolon
+            | Token::Comma
+            | Token::In
+            | Token::Else
+            | Token::Try
+            | Token::Finally
+            | Token::Async
+            | Token::Await
+            | Token::Import
+            | Token::From
+            | Token::As
+    )
+}
+
+// 次の行がこの字句で始まっていれば、前の行の続き
+fn continues_before(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Dot | Token::Pipe | Token::Else | Token::Catch | Token::Finally | Token::LBrace | Token::EOF
+    )
+}
+
+// 式の終わりになりうる字句
+fn ends_expression(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Int(_) | Token::BigInt(_) | Token::String(_) | Token::Char(_) | Token::Ident(_) | Token::RParen | Token::RBracket | Token::Question
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::lexer::tokenizer;
+
+    fn semicolons(source: &str) -> Result<Vec<Token>, String> {
+        let tokens = tokenizer(source).expect("Tokenization failed");
+        insert_semicolons(tokens, 1).map(|(tokens, _)| tokens)
+    }
+
+    #[test]
+    fn test_newline_ends_statement() {
+        assert_eq!(semicolons("x = 1\ny = 2\n").unwrap(), vec![
+            Token::Ident("x".to_string()),
+            Token::Assignment,
+            Token::Int(1),
+            Token::Semicolon,
+            Token::Ident("y".to_string()),
+            Token::Assignment,
+            Token::Int(2),
+            Token::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_line_continuations() {
+        // 開いた括弧の中、行末の演算子、行頭の `.` / `|>` / `else`
+        for source in [
+            "f(1,\n2)",
+            "[1,\n2]",
+            "1 +\n2",
+            "s\n.trim()",
+            "xs\n|> f()",
+            "if (x) { 1 }\nelse { 2 }",
+            "while (x)\n{ 1 }",
+            "\n\nx\n\n",
+        ] {
+            let tokens = semicolons(source).unwrap();
+            assert!(!tokens.contains(&Token::Semicolon), "inserted ';' in {:?}: {:?}", source, tokens);
+        }
+    }
+
+    #[test]
+    fn test_token_lines() {
+        let tokens = tokenizer("x = (1 +\n2)\n\ny / 0\n").unwrap();
+        let (tokens, lines) = insert_semicolons(tokens.clone(), 1).unwrap();
+        assert_eq!(tokens.len(), lines.len());
+        assert_eq!(lines, vec![1, 1, 1, 1, 1, 2, 2, 2, 4, 4, 4, 5]);
+        assert_eq!(tokens[8], Token::Ident("y".to_string()));
+        let tokens = tokenizer("x = (1 +\n2)\n\ny / 0\n").unwrap();
+        let (_, lines) = insert_semicolons(tokens, 10).unwrap();
+        assert_eq!(lines, vec![10, 10, 10, 10, 10, 11, 11, 11, 13, 13, 13, 14]);
+    }
+
+    #[test]
+    fn test_ambiguous_line_break_is_an_error() {
+        assert_eq!(
+            semicolons("a\n(b)"),
+            Err("Ambiguous line break before LParen on line 2: end the previous line with ';' or join the lines".to_string())
+        );
+        assert!(semicolons("a\n[1]").is_err());
+        assert!(semicolons("a;\n(b)").is_ok());
+    }
+}
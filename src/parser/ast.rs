@@ -1,13 +1,33 @@
+use num_bigint::BigInt;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     FunctionDef {
         name: String,
-        params: Vec<String>,
+        params: Vec<Param>,
+        // `-> int` の戻り値の型(書かなければ検査しない)
+        returns: Option<String>,
         body: Box<Expr>,
+        kind: FunctionKind,
+        visibility: Visibility,
     },
     FunctionCall {
         name: String,
         args: Vec<Expr>,
+        // f(1, y: 2) の `y: 2`
+        named_args: Vec<(String, Expr)>,
+    },
+    // receiver.method(args)
+    MethodCall {
+        receiver: Box<Expr>,
+        method: String,
+        args: Vec<Expr>,
+        named_args: Vec<(String, Expr)>,
+    },
+    // input |> f(a) |> g() は f(input, a) の結果を g の第一引数に渡す
+    Pipeline {
+        input: Box<Expr>,
+        stages: Vec<Expr>,
     },
     IfExpr {
         condition: Box<Expr>,
@@ -18,25 +38,233 @@ pub enum Expr {
         condition: Box<Expr>,
         body: Box<Expr>,
     },
+    VariableDecl {
+        name: String,
+        value: Box<Expr>,
+        constant: bool,
+        visibility: Visibility,
+    },
+    // for (variable in iterable) { body }
+    ForIn {
+        variable: String,
+        iterable: Box<Expr>,
+        body: Box<Expr>,
+    },
     Assignment {
         name: String,
         value: Box<Expr>,
     },
+    // xs[i] = 式
+    IndexAssignment {
+        target: Box<Expr>,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    // x += 式、xs[i] -= 式、x++ など(代入先の式は一度だけ評価する)
+    CompoundAssignment {
+        target: Box<Expr>,
+        op: Op,
+        value: Box<Expr>,
+        line: usize,
+    },
+    // lineは演算子がある行(0での割り算などのエラーの位置に使う)
     BinaryOp {
         left: Box<Expr>,
         op: Op,
         right: Box<Expr>,
+        line: usize,
     },
     Literal(Literal),
+    List(Vec<Expr>),
+    // start..end / start..=end
+    Range {
+        start: Box<Expr>,
+        end: Box<Expr>,
+        inclusive: bool,
+    },
+    Index {
+        target: Box<Expr>,
+        index: Box<Expr>,
+    },
     Variable(String),
     Block(Vec<Expr>),
     Return(Box<Expr>),
+    Throw(Box<Expr>),
+    // expr? (Err/Noneなら関数から早期リターンする)
+    Propagate(Box<Expr>),
+    // yield expr; (文としてだけ書ける)
+    Yield(Box<Expr>),
+    // await expr
+    Await(Box<Expr>),
+    // defer expr; (囲んでいるブロックを抜けるときに評価する)
+    Defer(Box<Expr>),
+    // class Name extends Parent { method(params) { ... } }
+    ClassDef {
+        name: String,
+        parent: Option<String>,
+        methods: Vec<MethodDef>,
+        visibility: Visibility,
+    },
+    // trait Name { required(self); provided(self) { ... } }
+    TraitDef {
+        name: String,
+        required: Vec<String>,
+        defaults: Vec<MethodDef>,
+        visibility: Visibility,
+    },
+    // impl Trait for Class { ... }
+    ImplDef {
+        trait_name: String,
+        class_name: String,
+        methods: Vec<MethodDef>,
+    },
+    // target.name
+    Field {
+        target: Box<Expr>,
+        name: String,
+    },
+    // target.name = value
+    FieldAssignment {
+        target: Box<Expr>,
+        name: String,
+        value: Box<Expr>,
+    },
+    // macro name(params) { template }
+    // マクロは評価の前に展開され、評価器には渡らない
+    MacroDef {
+        name: String,
+        params: Vec<String>,
+        body: Vec<Expr>,
+    },
+    // name!(args) / name!(args) { block } (後ろのブロックは最後の引数になる)
+    MacroCall {
+        name: String,
+        args: Vec<Expr>,
+    },
+    // import "path" as name
+    Import {
+        path: String,
+        alias: String,
+    },
+    // from "path" import a, b
+    ImportNames {
+        path: String,
+        names: Vec<String>,
+    },
+    // try { } catch (e) { } finally { }
+    // catchとfinallyは少なくともどちらか一方がある
+    TryCatch {
+        body: Box<Expr>,
+        catch: Option<(String, Box<Expr>)>,
+        finally: Option<Box<Expr>>,
+    },
+}
+
+// 関数の仮引数
+// `y = 10` ならdefaultに式が入り、`...xs` ならvariadicがtrueになる
+// `x: int` ならannotationに型の名前が入る(可変長引数では要素ごとの型)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub default: Option<Expr>,
+    pub variadic: bool,
+    pub annotation: Option<String>,
+}
+
+impl Param {
+    pub fn new(name: &str) -> Self {
+        Param {
+            name: name.to_string(),
+            default: None,
+            variadic: false,
+            annotation: None,
+        }
+    }
+}
+
+// 関数の種類
+// function* と書かなくても、本体にyieldがあればジェネレーターになる
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FunctionKind {
+    Normal,
+    Generator,
+    Async,
+}
+
+// モジュールのトップレベルの定義を、importした側から使えるかどうか
+// pubを付けた関数・定数・クラス・トレイトだけが公開される
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Visibility {
+    #[default]
+    Private,
+    Public,
+}
+
+impl Expr {
+    // 文の中にyieldがあるか(入れ子の関数やクラスの中は見ない)
+    pub fn contains_yield(&self) -> bool {
+        self.contains_statement(&|expr| matches!(expr, Expr::Yield(_)))
+    }
+
+    // 文の中に値を返すreturnがあるか(値のないreturnはUnitを返す)
+    pub fn contains_value_return(&self) -> bool {
+        self.contains_statement(&|expr| matches!(expr, Expr::Return(value) if **value != Expr::Literal(Literal::Unit)))
+    }
+
+    // 文の中に中断できる形のawaitがあるか
+    pub fn contains_await(&self) -> bool {
+        self.contains_statement(&Expr::is_await_statement)
+    }
+
+    // 中断できる形のawait: `await e`、`let x = await e`、`x = await e`、`return await e`
+    pub fn is_await_statement(&self) -> bool {
+        match self {
+            Expr::Await(_) => true,
+            Expr::VariableDecl { value, .. } | Expr::Assignment { value, .. } | Expr::Return(value) => {
+                matches!(**value, Expr::Await(_))
+            },
+            _ => false,
+        }
+    }
+
+    fn contains_statement(&self, found: &dyn Fn(&Expr) -> bool) -> bool {
+        if found(self) {
+            return true;
+        }
+        match self {
+            Expr::Block(statements) => statements.iter().any(|statement| statement.contains_statement(found)),
+            Expr::IfExpr { consequence, alternative, .. } => {
+                consequence.contains_statement(found)
+                    || alternative.as_ref().is_some_and(|alt| alt.contains_statement(found))
+            },
+            Expr::WhileLoop { body, .. } | Expr::ForIn { body, .. } => body.contains_statement(found),
+            Expr::TryCatch { body, catch, finally } => {
+                body.contains_statement(found)
+                    || catch.as_ref().is_some_and(|(_, handler)| handler.contains_statement(found))
+                    || finally.as_ref().is_some_and(|finally| finally.contains_statement(found))
+            },
+            Expr::Defer(action) => action.contains_statement(found),
+            _ => false,
+        }
+    }
+}
+
+// クラスのメソッド定義
+#[derive(Clone, Debug, PartialEq)]
+pub struct MethodDef {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub returns: Option<String>,
+    pub body: Expr,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Literal {
     Int(i64),
+    // 末尾にnを付けたか、intに収まらない整数リテラル
+    BigInt(BigInt),
     String(String),
+    Char(char),
     Unit,
 }
 
@@ -46,6 +274,12 @@ pub enum Op {
     Subtract,
     Multiply,
     Divide,
+    Modulo,
     LessThan,
     GreaterThan,
+    LessThanEqual,
+    GreaterThanEqual,
+    Equal,
+    NotEqual,
+    In,
 }
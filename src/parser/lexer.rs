@@ -1,15 +1,17 @@
 #![allow(dead_code)]
 use crate::parser::token::Token;
+use unicode_xid::UnicodeXID;
 use nom::{
     IResult,
     //error::{ParseError},
     character::complete::{
         //space0,
         //space1,
-        multispace0,
-        multispace1,
+        //multispace0,
+        //multispace1,
         //none_of,
         char,
+        satisfy,
         //line_ending,
         digit1,
     },
@@ -33,18 +35,19 @@ use nom::{
         recognize,
         //opt,
         map,
-        //value,
+        value,
         //all_consuming,
     },
     multi::{
         many0,
+        fold_many0,
         //many1,
         //separated_list0,
     },
     sequence::{
         pair,
         delimited,
-        //preceded,
+        preceded,
         //tuple,
     },
     //error::VerboseError,
@@ -58,28 +61,81 @@ pub fn display_tokens(tokens: &[Token]) {
 }
 
 // 整数リテラルを解析
+// `123n` のように末尾にnを付けるか、intに収まらないほど大きければ多倍長整数になる
 fn integer(input: &str) -> IResult<&str, Token> {
     let (input, int_str) = digit1(input)?;
-    let int = int_str.parse::<i64>().unwrap();
-    Ok((input, Token::Int(int)))
+    let (input, big) = match input.strip_prefix('n') {
+        Some(rest) if !rest.starts_with(|c: char| c.is_xid_continue()) => (rest, true),
+        _ => (input, false),
+    };
+    match int_str.parse::<i64>() {
+        Ok(int) if !big => Ok((input, Token::Int(int))),
+        _ => Ok((input, Token::BigInt(int_str.parse().unwrap()))),
+    }
 }
 
 // 文字列リテラルを解析
 fn string_literal(input: &str) -> IResult<&str, Token> {
+    // 開きの引用符の後ろの空白は文字列の一部なので読み飛ばさない
     let string_parser = delimited(
-        ws(char('"')),
-        take_while(|c: char| c != '"'),
+        char('"'),
+        fold_many0(
+            alt((escape, satisfy(|c| c != '"' && c != '\\'))),
+            String::new,
+            |mut s, c| {
+                s.push(c);
+                s
+            },
+        ),
         ws(char('"'))
     );
-    map(string_parser, |s: &str| Token::String(s.to_string()))(input)
+    map(string_parser, Token::String)(input)
+}
+
+// 文字列と文字のリテラルで使えるエスケープ
+// \n \t \r \0 \\ \' \" のほかは字句にできないのでエラーになる
+fn escape(input: &str) -> IResult<&str, char> {
+    preceded(
+        char('\\'),
+        alt((
+            value('\n', char('n')),
+            value('\t', char('t')),
+            value('\r', char('r')),
+            value('\0', char('0')),
+            value('\\', char('\\')),
+            value('\'', char('\'')),
+            value('"', char('"')),
+        ))
+    )(input)
+}
+
+// 文字リテラルを解析
+// 中身はちょうど一つのコードポイント('が' は書けるが、濁点を結合した 'か゛' は二つなので書けない)
+fn char_literal(input: &str) -> IResult<&str, Token> {
+    let char_parser = delimited(
+        char('\''),
+        alt((escape, satisfy(|c| c != '\'' && c != '\n' && c != '\\'))),
+        ws(char('\''))
+    );
+    map(char_parser, Token::Char)(input)
+}
+
+// 改行以外の空白
+// 改行は文の区切りになるのでトークンとして残す
+fn space0(input: &str) -> IResult<&str, &str> {
+    take_while(|c: char| c.is_whitespace() && c != '\n')(input)
+}
+
+fn space1(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_whitespace() && c != '\n')(input)
 }
 
 // 空白をスキップする関数
-fn ws<'a, F: 'a, O>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+fn ws<'a, F, O>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O>
 where
-    F: Fn(&'a str) -> IResult<&'a str, O>,
+    F: Fn(&'a str) -> IResult<&'a str, O> + 'a,
 {
-    delimited(multispace0, inner, multispace0)
+    delimited(space0, inner, space0)
 }
 
 
@@ -134,6 +190,62 @@ fn double_equal(input: &str) -> IResult<&str, Token> {
     map(ws(tag("==")), |_| Token::DoubleEqual)(input)
 }
 
+// '!='
+fn not_equal(input: &str) -> IResult<&str, Token> {
+    map(ws(tag("!=")), |_| Token::NotEqual)(input)
+}
+
+// '<='
+fn less_than_equal(input: &str) -> IResult<&str, Token> {
+    map(ws(tag("<=")), |_| Token::LessThanEqual)(input)
+}
+
+// '>='
+fn greater_than_equal(input: &str) -> IResult<&str, Token> {
+    map(ws(tag(">=")), |_| Token::GreaterThanEqual)(input)
+}
+
+// '+=', '-=', '*=', '/=', '%='
+fn compound_assignment(input: &str) -> IResult<&str, Token> {
+    alt((
+        map(ws(tag("+=")), |_| Token::PlusAssign),
+        map(ws(tag("-=")), |_| Token::MinusAssign),
+        map(ws(tag("*=")), |_| Token::StarAssign),
+        map(ws(tag("/=")), |_| Token::SlashAssign),
+        map(ws(tag("%=")), |_| Token::ModuloAssign),
+    ))(input)
+}
+
+// '++'
+fn increment(input: &str) -> IResult<&str, Token> {
+    map(ws(tag("++")), |_| Token::Increment)(input)
+}
+
+// '--'
+fn decrement(input: &str) -> IResult<&str, Token> {
+    map(ws(tag("--")), |_| Token::Decrement)(input)
+}
+
+// '|>'
+fn pipe(input: &str) -> IResult<&str, Token> {
+    map(ws(tag("|>")), |_| Token::Pipe)(input)
+}
+
+// '->'
+fn arrow(input: &str) -> IResult<&str, Token> {
+    map(ws(tag("->")), |_| Token::Arrow)(input)
+}
+
+// '?'
+// name!(...) のマクロ呼び出し
+fn bang(input: &str) -> IResult<&str, Token> {
+    map(ws(char('!')), |_| Token::Bang)(input)
+}
+
+fn question(input: &str) -> IResult<&str, Token> {
+    map(ws(char('?')), |_| Token::Question)(input)
+}
+
 // '('
 fn l_paren(input: &str) -> IResult<&str, Token> {
     map(ws(char('(')), |_| Token::LParen)(input)
@@ -154,11 +266,51 @@ fn r_brace(input: &str) -> IResult<&str, Token> {
     map(ws(char('}')), |_| Token::RBrace)(input)
 }
 
+// '['
+fn l_bracket(input: &str) -> IResult<&str, Token> {
+    map(ws(char('[')), |_| Token::LBracket)(input)
+}
+
+// ']'
+fn r_bracket(input: &str) -> IResult<&str, Token> {
+    map(ws(char(']')), |_| Token::RBracket)(input)
+}
+
+// ':'
+fn colon(input: &str) -> IResult<&str, Token> {
+    map(ws(char(':')), |_| Token::Colon)(input)
+}
+
+// '...'
+fn ellipsis(input: &str) -> IResult<&str, Token> {
+    map(ws(tag("...")), |_| Token::Ellipsis)(input)
+}
+
+// '..'
+fn dot_dot(input: &str) -> IResult<&str, Token> {
+    map(ws(tag("..")), |_| Token::DotDot)(input)
+}
+
+// '..='
+fn dot_dot_equal(input: &str) -> IResult<&str, Token> {
+    map(ws(tag("..=")), |_| Token::DotDotEqual)(input)
+}
+
+// '.'
+fn dot(input: &str) -> IResult<&str, Token> {
+    map(ws(char('.')), |_| Token::Dot)(input)
+}
+
 // ';'
 fn semicolon(input: &str) -> IResult<&str, Token> {
     map(ws(char(';')), |_| Token::Semicolon)(input)
 }
 
+// 改行
+fn newline(input: &str) -> IResult<&str, Token> {
+    map(ws(char('\n')), |_| Token::Newline)(input)
+}
+
 // ','
 fn comma(input: &str) -> IResult<&str, Token> {
     map(ws(char(',')), |_| Token::Comma)(input)
@@ -166,22 +318,54 @@ fn comma(input: &str) -> IResult<&str, Token> {
 
 // キーワードの解析関数
 fn keyword(input: &str) -> IResult<&str, Token> {
+    alt((control_keyword, declaration_keyword))(input).and_then(|(next_input, token)| {
+        space1(next_input).map(|(final_input, _)| (final_input, token))
+    })
+}
+
+fn control_keyword(input: &str) -> IResult<&str, Token> {
     alt((
-        map(tag("function"), |_| Token::Function),
         map(tag("if"), |_| Token::If),
         map(tag("else"), |_| Token::Else),
         map(tag("while"), |_| Token::While),
         map(tag("return"), |_| Token::Return),
-    ))(input).and_then(|(next_input, token)| {
-        multispace1(next_input).map(|(final_input, _)| (final_input, token))
-    })
+        map(tag("for"), |_| Token::For),
+        map(tag("in"), |_| Token::In),
+        map(tag("throw"), |_| Token::Throw),
+        map(tag("try"), |_| Token::Try),
+        map(tag("catch"), |_| Token::Catch),
+        map(tag("finally"), |_| Token::Finally),
+        map(tag("defer"), |_| Token::Defer),
+        map(tag("yield"), |_| Token::Yield),
+        map(tag("await"), |_| Token::Await),
+    ))(input)
+}
+
+fn declaration_keyword(input: &str) -> IResult<&str, Token> {
+    alt((
+        map(tag("function"), |_| Token::Function),
+        map(tag("let"), |_| Token::Let),
+        map(tag("const"), |_| Token::Const),
+        map(tag("class"), |_| Token::Class),
+        map(tag("extends"), |_| Token::Extends),
+        map(tag("trait"), |_| Token::Trait),
+        map(tag("impl"), |_| Token::Impl),
+        map(tag("async"), |_| Token::Async),
+        map(tag("macro"), |_| Token::Macro),
+        map(tag("import"), |_| Token::Import),
+        map(tag("from"), |_| Token::From),
+        map(tag("as"), |_| Token::As),
+        map(tag("pub"), |_| Token::Pub),
+        map(tag("export"), |_| Token::Pub),
+    ))(input)
 }
 
 // 識別子を解析
+// UnicodeのXID_Startか`_`で始まり、XID_Continueが続く(`名前`や`café`も識別子になる)
 fn identifier(input: &str) -> IResult<&str, Token> {
     //println!("Trying identifier with input: {}", input);
-    let start_parser = take_while1(|c: char| c.is_alphabetic() || c == '_');
-    let rest_parser = take_while(|c: char| c.is_alphanumeric() || c == '_');
+    let start_parser = take_while1(|c: char| c.is_xid_start() || c == '_');
+    let rest_parser = take_while(|c: char| c.is_xid_continue());
     let mut combined_parser = recognize(pair(start_parser, rest_parser));
 
     let result = combined_parser(input);
@@ -194,10 +378,58 @@ fn identifier(input: &str) -> IResult<&str, Token> {
     result.map(|(remaining, ident)| (remaining, Token::Ident(ident.to_string())))
 }
 
-pub fn tokenizer(input: &str) -> IResult<&str, Vec<Token>> {
-    let (input, _) = multispace0(input)?;
+// 演算子(長いものから順に試す)
+fn operator(input: &str) -> IResult<&str, Token> {
+    alt((
+        increment,
+        decrement,
+        compound_assignment,
+        arrow,
+        plus,
+        minus,
+        star,
+        slash,
+        modulo,
+        less_than_equal,
+        greater_than_equal,
+        less_than,
+        greater_than,
+        double_equal,
+        not_equal,
+        bang,
+        assignment,
+        pipe,
+        question,
+    ))(input)
+}
+
+// 括弧や区切り記号
+fn punctuation(input: &str) -> IResult<&str, Token> {
+    alt((
+        l_paren,
+        r_paren,
+        l_brace,
+        r_brace,
+        l_bracket,
+        r_bracket,
+        semicolon,
+        comma,
+        colon,
+        ellipsis,
+        dot_dot_equal,
+        dot_dot,
+        dot,
+        newline,
+    ))(input)
+}
+
+// 字句にできない入力が残ったら、その位置(行と列は1から)をエラーにする
+pub fn tokenizer(source: &str) -> Result<Vec<Token>, String> {
+    let (input, _) = space0(source).map_err(|e| e.to_string())?;
 
-    let (remaining_input, mut tokens) = many0(
+    // キーワードと識別子の前の空白もここで読み飛ばす
+    let (remaining_input, mut tokens) = many0(preceded(
+        space0,
         alt((
             keyword,
             map(identifier, |ident: Token| {
@@ -207,38 +439,51 @@ pub fn tokenizer(input: &str) -> IResult<&str, Vec<Token>> {
                     Token::Ident(name) if name == "if" => Token::If,
                     Token::Ident(name) if name == "else" => Token::Else,
                     Token::Ident(name) if name == "return" => Token::Return,
+                    Token::Ident(name) if name == "let" => Token::Let,
+                    Token::Ident(name) if name == "const" => Token::Const,
+                    Token::Ident(name) if name == "for" => Token::For,
+                    Token::Ident(name) if name == "in" => Token::In,
+                    Token::Ident(name) if name == "throw" => Token::Throw,
+                    Token::Ident(name) if name == "try" => Token::Try,
+                    Token::Ident(name) if name == "catch" => Token::Catch,
+                    Token::Ident(name) if name == "finally" => Token::Finally,
+                    Token::Ident(name) if name == "defer" => Token::Defer,
+                    Token::Ident(name) if name == "class" => Token::Class,
+                    Token::Ident(name) if name == "extends" => Token::Extends,
+                    Token::Ident(name) if name == "trait" => Token::Trait,
+                    Token::Ident(name) if name == "impl" => Token::Impl,
+                    Token::Ident(name) if name == "yield" => Token::Yield,
+                    Token::Ident(name) if name == "async" => Token::Async,
+                    Token::Ident(name) if name == "await" => Token::Await,
+                    Token::Ident(name) if name == "macro" => Token::Macro,
+                    Token::Ident(name) if name == "import" => Token::Import,
+                    Token::Ident(name) if name == "from" => Token::From,
+                    Token::Ident(name) if name == "as" => Token::As,
+                    Token::Ident(name) if name == "pub" || name == "export" => Token::Pub,
                     _ => ident,
                 }
             }),
             integer,
             string_literal,
-            plus,
-            minus,
-            star,
-            slash,
-            modulo,
-            less_than,
-            greater_than,
-            double_equal,
-            assignment,
-            l_paren,
-            r_paren,
-            l_brace,
-            r_brace,
-            semicolon,
-            comma,
+            char_literal,
+            operator,
+            punctuation,
         )),
-    )(input)?;
+    ))(input).map_err(|e| e.to_string())?;
 
     //println!("Remaining input: {:?}", remaining_input); // 残りの入力を表示
     //println!("Tokens: {:?}", tokens); // 解析したトークンを表示 
     //display_tokens(&tokens);
-    // 入力が完全に消費された場合EOFトークンを追加
-    if remaining_input.is_empty() {
-        tokens.push(Token::EOF);
+    let remaining_input = space0(remaining_input).map_or(remaining_input, |(rest, _)| rest);
+    if let Some(c) = remaining_input.chars().next() {
+        let consumed = &source[..source.len() - remaining_input.len()];
+        let line = consumed.matches('\n').count() + 1;
+        let column = consumed.rsplit('\n').next().map_or(0, |last| last.chars().count()) + 1;
+        return Err(format!("Unexpected character {:?} at line {}, column {}", c, line, column));
     }
+    tokens.push(Token::EOF);
 
-    Ok((remaining_input, tokens))
+    Ok(tokens)
 }
 
 #[cfg(test)]
@@ -250,14 +495,76 @@ mod tests {
         assert_eq!(integer("123 "), Ok((" ", Token::Int(123))));
     }
 
+    #[test]
+    fn test_big_integer() {
+        assert_eq!(integer("123n "), Ok((" ", Token::BigInt(123.into()))));
+        assert_eq!(
+            integer("99999999999999999999;"),
+            Ok((";", Token::BigInt("99999999999999999999".parse().unwrap())))
+        );
+        // nで始まる識別子は続けて書けない
+        let tokens = tokenizer("1 next").unwrap();
+        assert_eq!(tokens[0], Token::Int(1));
+        assert_eq!(integer("1name"), Ok(("name", Token::Int(1))));
+    }
+
     #[test]
     fn test_identifier() {
         assert_eq!(identifier("testVar "), Ok((" ", Token::Ident("testVar".to_string()))));
     }
 
+    #[test]
+    fn test_unicode_identifiers() {
+        let tokens = tokenizer("let 名前 = café_2 + _ñ").unwrap();
+        assert_eq!(tokens, vec![
+            Token::Let,
+            Token::Ident("名前".to_string()),
+            Token::Assignment,
+            Token::Ident("café_2".to_string()),
+            Token::Plus,
+            Token::Ident("_ñ".to_string()),
+            Token::EOF,
+        ]);
+        // 数字や記号、絵文字では始められない
+        assert!(identifier("１つ").is_err());
+        assert!(identifier("🐱").is_err());
+    }
+
+    #[test]
+    fn test_char_literal() {
+        assert_eq!(char_literal("'a' "), Ok(("", Token::Char('a'))));
+        assert_eq!(char_literal("'猫'"), Ok(("", Token::Char('猫'))));
+        assert_eq!(char_literal("'🐱'"), Ok(("", Token::Char('🐱'))));
+        assert_eq!(char_literal("' '"), Ok(("", Token::Char(' '))));
+        // 二つ以上のコードポイントや空の文字は書けない
+        assert!(char_literal("'ab'").is_err());
+        assert!(char_literal("'か\u{3099}'").is_err());
+        assert!(char_literal("''").is_err());
+        // 文字列と同じエスケープが使える
+        assert_eq!(char_literal(r"'\n'"), Ok(("", Token::Char('\n'))));
+        assert_eq!(char_literal(r"'\t'"), Ok(("", Token::Char('\t'))));
+        assert_eq!(char_literal(r"'\''"), Ok(("", Token::Char('\''))));
+        assert_eq!(char_literal(r"'\\'"), Ok(("", Token::Char('\\'))));
+        assert!(char_literal(r"'\q'").is_err());
+    }
+
     #[test]
     fn test_string_literal() {
         assert_eq!(string_literal("\"hello world\" "), Ok(("", Token::String("hello world".to_string()))));
+        assert_eq!(
+            string_literal(r#""a\tb\n\"c\" \\ \'d\'" "#),
+            Ok(("", Token::String("a\tb\n\"c\" \\ 'd'".to_string())))
+        );
+        assert!(string_literal(r#""\q""#).is_err());
+    }
+
+    // 字句にできない入力が残れば、その行と列をエラーにする
+    #[test]
+    fn test_unexpected_input() {
+        assert_eq!(tokenizer("let x = 1\nlet y = #"), Err("Unexpected character '#' at line 2, column 9".to_string()));
+        assert_eq!(tokenizer("\"open"), Err("Unexpected character '\"' at line 1, column 1".to_string()));
+        assert_eq!(tokenizer("'猫 + 1"), Err("Unexpected character '\\'' at line 1, column 1".to_string()));
+        assert_eq!(tokenizer("x  \n  "), Ok(vec![Token::Ident("x".to_string()), Token::Newline, Token::EOF]));
     }
 
     #[test]
@@ -269,11 +576,78 @@ mod tests {
         assert_eq!(modulo("%"), Ok(("", Token::Modulo)));
     }
 
+    #[test]
+    fn test_compound_assignment_operators() {
+        let tokens = tokenizer("x += 1; x -= 1; x *= 2; x /= 2; x %= 3; x++; x--;").unwrap();
+        let operators: Vec<Token> = tokens.into_iter()
+            .filter(|t| !matches!(t, Token::Ident(_) | Token::Int(_) | Token::Semicolon | Token::EOF))
+            .collect();
+        assert_eq!(operators, vec![
+            Token::PlusAssign,
+            Token::MinusAssign,
+            Token::StarAssign,
+            Token::SlashAssign,
+            Token::ModuloAssign,
+            Token::Increment,
+            Token::Decrement,
+        ]);
+        assert_eq!(tokenizer("1 + +1").unwrap()[1], Token::Plus);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let tokens = tokenizer("for (i in 0..=n) { xs[1..3]; }").unwrap();
+        assert_eq!(tokens, vec![
+            Token::For,
+            Token::LParen,
+            Token::Ident("i".to_string()),
+            Token::In,
+            Token::Int(0),
+            Token::DotDotEqual,
+            Token::Ident("n".to_string()),
+            Token::RParen,
+            Token::LBrace,
+            Token::Ident("xs".to_string()),
+            Token::LBracket,
+            Token::Int(1),
+            Token::DotDot,
+            Token::Int(3),
+            Token::RBracket,
+            Token::Semicolon,
+            Token::RBrace,
+            Token::EOF,
+        ]);
+        let tokens = tokenizer("index format").unwrap();
+        assert_eq!(tokens[0], Token::Ident("index".to_string()));
+        assert_eq!(tokens[1], Token::Ident("format".to_string()));
+    }
+
+    #[test]
+    fn test_newlines_are_tokens() {
+        let tokens = tokenizer("x\n  \"  padded\"\n").unwrap();
+        assert_eq!(tokens, vec![
+            Token::Ident("x".to_string()),
+            Token::Newline,
+            Token::String("  padded".to_string()),
+            Token::Newline,
+            Token::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_pipe() {
+        let tokens = tokenizer("xs |> sum()").unwrap();
+        assert_eq!(tokens[1], Token::Pipe);
+    }
+
     #[test]
     fn test_comparison_operators() {
         assert_eq!(less_than("<"), Ok(("", Token::LessThan)));
         assert_eq!(greater_than(">"), Ok(("", Token::GreaterThan)));
         assert_eq!(double_equal("=="), Ok(("", Token::DoubleEqual)));
+        let tokens = tokenizer("a <= b >= c != d < e").unwrap();
+        let operators: Vec<Token> = tokens.into_iter().filter(|t| !matches!(t, Token::Ident(_) | Token::EOF)).collect();
+        assert_eq!(operators, vec![Token::LessThanEqual, Token::GreaterThanEqual, Token::NotEqual, Token::LessThan]);
     }
 
     #[test]
@@ -289,6 +663,29 @@ mod tests {
         assert_eq!(semicolon(";"), Ok(("", Token::Semicolon)));
     }
 
+    #[test]
+    fn test_parameter_punctuation() {
+        assert_eq!(l_bracket("["), Ok(("", Token::LBracket)));
+        assert_eq!(r_bracket("]"), Ok(("", Token::RBracket)));
+        assert_eq!(colon(":"), Ok(("", Token::Colon)));
+        assert_eq!(ellipsis("..."), Ok(("", Token::Ellipsis)));
+    }
+
+    #[test]
+    fn test_method_call() {
+        let tokens = tokenizer("s.trim()").unwrap();
+        assert_eq!(tokens, vec![
+            Token::Ident("s".to_string()),
+            Token::Dot,
+            Token::Ident("trim".to_string()),
+            Token::LParen,
+            Token::RParen,
+            Token::EOF,
+        ]);
+        let tokens = tokenizer("f(...xs)").unwrap();
+        assert_eq!(tokens[2], Token::Ellipsis);
+    }
+
     // #[test]
     // fn test_keywords() {
     //     assert_eq!(keyword("function"), Ok(("", Token::Function)));
@@ -301,7 +698,7 @@ mod tests {
     #[test]
     fn test_valid_assignment() {
         let input = "hello = 10;";
-        if let Ok((_, tokens)) = tokenizer(input) {
+        if let Ok(tokens) = tokenizer(input) {
             assert_eq!(tokens, vec![
                 Token::Ident("hello".to_string()),
                 Token::Assignment,
@@ -314,6 +711,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_variable_declarations() {
+        let tokens = tokenizer("let x = 1; const y = 2;").unwrap();
+        assert_eq!(tokens, vec![
+            Token::Let,
+            Token::Ident("x".to_string()),
+            Token::Assignment,
+            Token::Int(1),
+            Token::Semicolon,
+            Token::Const,
+            Token::Ident("y".to_string()),
+            Token::Assignment,
+            Token::Int(2),
+            Token::Semicolon,
+            Token::EOF,
+        ]);
+    }
+
     #[test]
     fn test_function_def() {
         let input = "
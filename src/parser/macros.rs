@@ -0,0 +1,351 @@
+// マクロの展開
+//
+// 構文解析の後、評価の前にプログラム全体を一度たどり、
+// `name!(args)` をマクロ定義のテンプレートで置き換える
+// - 引数は評価せず、式のまま仮引数の位置に埋め込む
+// - テンプレートの中で宣言した変数は呼び出しごとに `name#番号` に改名する
+//   `#` は識別子に使えないので、利用者の変数と衝突しない(衛生的なマクロ)
+// - 仮引数を変数名の位置(`x = ...`、`x(...)`)に書くと、渡された変数名になる
+//   展開結果はブロックなので、仮引数を `let`/`const` で宣言しても呼び出し側には残らない
+//   そのため仮引数の宣言はエラーにする
+// - 展開結果にマクロ呼び出しがあれば、それも展開する
+use std::collections::{HashMap, HashSet};
+use crate::parser::ast::{Expr, MethodDef, Param};
+
+// 展開の入れ子の上限(自分自身を呼ぶマクロで止まらなくなるのを防ぐ)
+const MAX_DEPTH: usize = 64;
+
+#[derive(Default)]
+pub struct Expander {
+    macros: HashMap<String, Macro>,
+    // 改名に使う通し番号
+    counter: usize,
+    depth: usize,
+    // trueなら展開後のASTを表示する
+    pub debug: bool,
+}
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Expr>,
+}
+
+// 一回の展開で使う置き換え表
+struct Template {
+    arguments: HashMap<String, Expr>,
+    renames: HashMap<String, String>,
+}
+
+impl Expander {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // プログラムのマクロを展開する
+    // REPLでは同じExpanderを使い続けるので、前の入力で定義したマクロも使える
+    pub fn expand_program(&mut self, program: Expr) -> Result<Expr, String> {
+        let expanded = self.expand(program)?;
+        if self.debug {
+            println!("{:#?}", expanded);
+        }
+        Ok(expanded)
+    }
+
+    fn expand(&mut self, expr: Expr) -> Result<Expr, String> {
+        match expr {
+            // マクロ定義は登録して、プログラムからは取り除く
+            Expr::Block(statements) => {
+                let mut expanded = Vec::with_capacity(statements.len());
+                for statement in statements {
+                    match statement {
+                        Expr::MacroDef { name, params, body } => {
+                            self.macros.insert(name, Macro { params, body });
+                        },
+                        statement => expanded.push(self.expand(statement)?),
+                    }
+                }
+                Ok(Expr::Block(expanded))
+            },
+            Expr::MacroCall { name, args } => {
+                if self.depth >= MAX_DEPTH {
+                    return Err(format!("Macro '{}' expands too deeply (more than {} levels)", name, MAX_DEPTH));
+                }
+                let instance = self.instantiate(&name, args)?;
+                self.depth += 1;
+                let expanded = self.expand(instance);
+                self.depth -= 1;
+                expanded
+            },
+            expr => map_children(expr, &mut |child| self.expand(child)),
+        }
+    }
+
+    // テンプレートに引数を埋め込み、宣言された変数を改名したブロックを作る
+    fn instantiate(&mut self, name: &str, args: Vec<Expr>) -> Result<Expr, String> {
+        let definition = self.macros.get(name).cloned()
+            .ok_or_else(|| format!("Macro '{}' is not defined", name))?;
+        if args.len() != definition.params.len() {
+            return Err(format!("Macro '{}' expects {} arguments, got {}", name, definition.params.len(), args.len()));
+        }
+
+        self.counter += 1;
+        let mut bindings = HashSet::new();
+        for statement in &definition.body {
+            collect_bindings(statement, &mut bindings);
+        }
+        let renames = bindings.into_iter()
+            .filter(|binding| !definition.params.contains(binding))
+            .map(|binding| {
+                let renamed = format!("{}#{}", binding, self.counter);
+                (binding, renamed)
+            })
+            .collect();
+        let template = Template {
+            arguments: definition.params.into_iter().zip(args).collect(),
+            renames,
+        };
+
+        let body = definition.body.into_iter()
+            .map(|statement| template.rewrite(statement))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Expr::Block(body))
+    }
+}
+
+impl Template {
+    fn rewrite(&self, expr: Expr) -> Result<Expr, String> {
+        // 引数はすでに呼び出し側の式なので、中には入らない
+        if let Expr::Variable(name) = expr {
+            return Ok(match self.arguments.get(&name) {
+                Some(argument) => argument.clone(),
+                None => Expr::Variable(self.rename(name)),
+            });
+        }
+        let expr = map_children(expr, &mut |child| self.rewrite(child))?;
+        Ok(match expr {
+            Expr::VariableDecl { name, .. } if self.arguments.contains_key(&name) => {
+                return Err(format!("Macro parameter '{}' cannot be declared with let or const: the expansion has its own scope", name));
+            },
+            Expr::VariableDecl { name, value, constant, visibility } => Expr::VariableDecl { name: self.rename(name), value, constant, visibility },
+            Expr::Assignment { name, value } => Expr::Assignment { name: self.name(name)?, value },
+            Expr::FunctionCall { name, args, named_args } => Expr::FunctionCall { name: self.name(name)?, args, named_args },
+            Expr::ForIn { variable, iterable, body } => Expr::ForIn { variable: self.name(variable)?, iterable, body },
+            Expr::FunctionDef { name, params, returns, body, kind, visibility } => Expr::FunctionDef {
+                name,
+                returns,
+                params: params.into_iter()
+                    .map(|param| Ok(Param { name: self.name(param.name)?, ..param }))
+                    .collect::<Result<_, String>>()?,
+                body,
+                kind,
+                visibility,
+            },
+            Expr::TryCatch { body, catch, finally } => Expr::TryCatch {
+                body,
+                catch: match catch {
+                    Some((name, handler)) => Some((self.name(name)?, handler)),
+                    None => None,
+                },
+                finally,
+            },
+            expr => expr,
+        })
+    }
+
+    // 名前の位置に書かれた仮引数は、渡された変数の名前に置き換える
+    fn name(&self, name: String) -> Result<String, String> {
+        match self.arguments.get(&name) {
+            Some(Expr::Variable(argument)) => Ok(argument.clone()),
+            Some(_) => Err(format!("Macro parameter '{}' is used as a name, so its argument must be a variable", name)),
+            None => Ok(self.rename(name)),
+        }
+    }
+
+    fn rename(&self, name: String) -> String {
+        self.renames.get(&name).cloned().unwrap_or(name)
+    }
+}
+
+// テンプレートの中で宣言される変数の名前を集める
+fn collect_bindings(expr: &Expr, bindings: &mut HashSet<String>) {
+    match expr {
+        Expr::VariableDecl { name, .. } => {
+            bindings.insert(name.clone());
+        },
+        Expr::ForIn { variable, .. } => {
+            bindings.insert(variable.clone());
+        },
+        Expr::FunctionDef { params, .. } => {
+            bindings.extend(params.iter().map(|param| param.name.clone()));
+        },
+        Expr::TryCatch { catch: Some((name, _)), .. } => {
+            bindings.insert(name.clone());
+        },
+        _ => {},
+    }
+    let _ = map_children(expr.clone(), &mut |child| {
+        collect_bindings(&child, bindings);
+        Ok(child)
+    });
+}
+
+// 直下の子の式にfを適用して組み立て直す
+fn map_children(expr: Expr, f: &mut dyn FnMut(Expr) -> Result<Expr, String>) -> Result<Expr, String> {
+    let boxed = |expr: Box<Expr>, f: &mut dyn FnMut(Expr) -> Result<Expr, String>| f(*expr).map(Box::new);
+    Ok(match expr {
+        Expr::FunctionDef { name, params, returns, body, kind, visibility } => Expr::FunctionDef {
+            name,
+            params: map_params(params, f)?,
+            returns,
+            body: boxed(body, f)?,
+            kind,
+            visibility,
+        },
+        Expr::FunctionCall { name, args, named_args } => Expr::FunctionCall {
+            name,
+            args: map_all(args, f)?,
+            named_args: map_named(named_args, f)?,
+        },
+        Expr::MethodCall { receiver, method, args, named_args } => Expr::MethodCall {
+            receiver: boxed(receiver, f)?,
+            method,
+            args: map_all(args, f)?,
+            named_args: map_named(named_args, f)?,
+        },
+        Expr::Pipeline { input, stages } => Expr::Pipeline { input: boxed(input, f)?, stages: map_all(stages, f)? },
+        Expr::IfExpr { condition, consequence, alternative } => Expr::IfExpr {
+            condition: boxed(condition, f)?,
+            consequence: boxed(consequence, f)?,
+            alternative: match alternative {
+                Some(alternative) => Some(boxed(alternative, f)?),
+                None => None,
+            },
+        },
+        Expr::WhileLoop { condition, body } => Expr::WhileLoop { condition: boxed(condition, f)?, body: boxed(body, f)? },
+        Expr::VariableDecl { name, value, constant, visibility } => Expr::VariableDecl { name, value: boxed(value, f)?, constant, visibility },
+        Expr::ForIn { variable, iterable, body } => Expr::ForIn { variable, iterable: boxed(iterable, f)?, body: boxed(body, f)? },
+        Expr::Assignment { name, value } => Expr::Assignment { name, value: boxed(value, f)? },
+        Expr::IndexAssignment { target, index, value } => Expr::IndexAssignment {
+            target: boxed(target, f)?,
+            index: boxed(index, f)?,
+            value: boxed(value, f)?,
+        },
+        Expr::CompoundAssignment { target, op, value, line } => Expr::CompoundAssignment { target: boxed(target, f)?, op, value: boxed(value, f)?, line },
+        Expr::BinaryOp { left, op, right, line } => Expr::BinaryOp { left: boxed(left, f)?, op, right: boxed(right, f)?, line },
+        Expr::List(items) => Expr::List(map_all(items, f)?),
+        Expr::Range { start, end, inclusive } => Expr::Range { start: boxed(start, f)?, end: boxed(end, f)?, inclusive },
+        Expr::Index { target, index } => Expr::Index { target: boxed(target, f)?, index: boxed(index, f)? },
+        Expr::Block(statements) => Expr::Block(map_all(statements, f)?),
+        Expr::Return(value) => Expr::Return(boxed(value, f)?),
+        Expr::Throw(value) => Expr::Throw(boxed(value, f)?),
+        Expr::Propagate(value) => Expr::Propagate(boxed(value, f)?),
+        Expr::Yield(value) => Expr::Yield(boxed(value, f)?),
+        Expr::Await(value) => Expr::Await(boxed(value, f)?),
+        Expr::Defer(value) => Expr::Defer(boxed(value, f)?),
+        Expr::ClassDef { name, parent, methods, visibility } => Expr::ClassDef { name, parent, methods: map_methods(methods, f)?, visibility },
+        Expr::TraitDef { name, required, defaults, visibility } => Expr::TraitDef { name, required, defaults: map_methods(defaults, f)?, visibility },
+        Expr::ImplDef { trait_name, class_name, methods } => Expr::ImplDef { trait_name, class_name, methods: map_methods(methods, f)? },
+        Expr::Field { target, name } => Expr::Field { target: boxed(target, f)?, name },
+        Expr::FieldAssignment { target, name, value } => Expr::FieldAssignment { target: boxed(target, f)?, name, value: boxed(value, f)? },
+        Expr::MacroDef { name, params, body } => Expr::MacroDef { name, params, body: map_all(body, f)? },
+        Expr::MacroCall { name, args } => Expr::MacroCall { name, args: map_all(args, f)? },
+        Expr::TryCatch { body, catch, finally } => Expr::TryCatch {
+            body: boxed(body, f)?,
+            catch: match catch {
+                Some((name, handler)) => Some((name, boxed(handler, f)?)),
+                None => None,
+            },
+            finally: match finally {
+                Some(finally) => Some(boxed(finally, f)?),
+                None => None,
+            },
+        },
+        expr @ (Expr::Literal(_) | Expr::Variable(_) | Expr::Import { .. } | Expr::ImportNames { .. }) => expr,
+    })
+}
+
+fn map_all(exprs: Vec<Expr>, f: &mut dyn FnMut(Expr) -> Result<Expr, String>) -> Result<Vec<Expr>, String> {
+    exprs.into_iter().map(f).collect()
+}
+
+fn map_named(args: Vec<(String, Expr)>, f: &mut dyn FnMut(Expr) -> Result<Expr, String>) -> Result<Vec<(String, Expr)>, String> {
+    args.into_iter().map(|(name, expr)| Ok((name, f(expr)?))).collect()
+}
+
+fn map_params(params: Vec<Param>, f: &mut dyn FnMut(Expr) -> Result<Expr, String>) -> Result<Vec<Param>, String> {
+    params.into_iter()
+        .map(|param| Ok(Param { default: param.default.map(&mut *f).transpose()?, ..param }))
+        .collect()
+}
+
+fn map_methods(methods: Vec<MethodDef>, f: &mut dyn FnMut(Expr) -> Result<Expr, String>) -> Result<Vec<MethodDef>, String> {
+    methods.into_iter()
+        .map(|method| {
+            Ok(MethodDef {
+                params: map_params(method.params, f)?,
+                body: f(method.body)?,
+                name: method.name,
+                returns: method.returns,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ast::Visibility;
+    use crate::parser::lexer::tokenizer;
+    use crate::parser::Parser;
+
+    fn expand(source: &str) -> Result<Expr, String> {
+        let tokens = tokenizer(source).expect("Tokenization failed");
+        let mut parser = Parser::new(tokens);
+        Expander::new().expand_program(parser.parse_tokens()?)
+    }
+
+    #[test]
+    fn test_expansion_substitutes_arguments() {
+        let expanded = expand("macro twice(e) { e; e }\ntwice!(f(1))").unwrap();
+        let call = Expr::FunctionCall {
+            name: "f".to_string(),
+            args: vec![Expr::Literal(crate::parser::ast::Literal::Int(1))],
+            named_args: vec![],
+        };
+        assert_eq!(expanded, Expr::Block(vec![Expr::Block(vec![call.clone(), call])]));
+    }
+
+    // テンプレートで宣言した変数は呼び出しごとに改名され、引数の中の同名の変数とは別になる
+    #[test]
+    fn test_hygienic_renaming() {
+        let expanded = expand("macro tmp(e) { let t = e; t }\ntmp!(t)\ntmp!(t)").unwrap();
+        let instance = |n: usize| Expr::Block(vec![
+            Expr::VariableDecl {
+                name: format!("t#{}", n),
+                value: Box::new(Expr::Variable("t".to_string())),
+                constant: false,
+                visibility: Visibility::Private,
+            },
+            Expr::Variable(format!("t#{}", n)),
+        ]);
+        assert_eq!(expanded, Expr::Block(vec![instance(1), instance(2)]));
+    }
+
+    #[test]
+    fn test_expansion_errors() {
+        assert_eq!(expand("nope!(1)"), Err("Macro 'nope' is not defined".to_string()));
+        assert_eq!(expand("macro m(a) { a }\nm!()"), Err("Macro 'm' expects 1 arguments, got 0".to_string()));
+        assert_eq!(
+            expand("macro m(a) { a = 1 }\nm!(1 + 2)"),
+            Err("Macro parameter 'a' is used as a name, so its argument must be a variable".to_string())
+        );
+        // 展開結果のブロックを抜けると消えてしまうので、仮引数は宣言できない
+        assert_eq!(
+            expand("macro def(x, v) { let x = v }\ndef!(y, 5)\ny"),
+            Err("Macro parameter 'x' cannot be declared with let or const: the expansion has its own scope".to_string())
+        );
+        assert!(expand("macro def(x) { const x = 1 }\ndef!(y)").is_err());
+        assert!(expand("macro forever() { forever!() }\nforever!()").unwrap_err().contains("expands too deeply"));
+    }
+}
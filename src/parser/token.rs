@@ -1,9 +1,20 @@
+use num_bigint::BigInt;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Int(i64),
+    BigInt(BigInt),
     String(String),
+    Char(char),
     Ident(String), // identifier
     Assignment,
+    PlusAssign,
+    MinusAssign,
+    StarAssign,
+    SlashAssign,
+    ModuloAssign,
+    Increment,
+    Decrement,
     Plus,
     Minus,
     Star,
@@ -11,17 +22,54 @@ pub enum Token {
     Modulo,
     LessThan,
     GreaterThan,
+    LessThanEqual,
+    GreaterThanEqual,
     DoubleEqual,
+    NotEqual,
+    Pipe,
+    // 戻り値の型の前の ->
+    Arrow,
+    Question,
+    Bang,
     LParen,
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
+    Colon,
+    Ellipsis,
+    DotDot,
+    DotDotEqual,
+    Dot,
     Semicolon,
+    Newline,
     Comma,
     Function,
     If,
     Else,
     While,
     Return,
+    Let,
+    Const,
+    For,
+    In,
+    Throw,
+    Try,
+    Catch,
+    Finally,
+    Defer,
+    Class,
+    Extends,
+    Trait,
+    Impl,
+    Yield,
+    Async,
+    Await,
+    Macro,
+    Import,
+    From,
+    As,
+    Pub,
     EOF,
 }
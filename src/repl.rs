@@ -1,11 +1,11 @@
 use std::io::{self, Write};
 use simlang::parser::lexer::tokenizer;
 use simlang::parser::Parser;
+use simlang::parser::macros::Expander;
 use simlang::interpreter::evaluator::{Evaluator, EvaluationResult};
-use simlang::parser::ast;
 
 
-pub fn run_repl() {
+pub fn run_repl(expand_macros: bool, prelude: bool) {
     let ascii_art = r#"
         _           _                   
     ___(_)_ __ ___ | | __ _ _ __   __ _ 
@@ -21,7 +21,12 @@ pub fn run_repl() {
     ";
     println!("\x1b[31m{}\x1b[0m", ascii_art);
     println!("{}", message);
-    let mut evaluator = Evaluator::new();
+    let mut evaluator = if prelude { Evaluator::new() } else { Evaluator::without_prelude() };
+    // 前の行で定義したマクロも使えるように、展開器は使い回す
+    let mut expander = Expander::new();
+    expander.debug = expand_macros;
+    // エラーの行の番号は、REPLに入力した行を通して数える
+    let mut line = 1;
 
     loop {
         print!("\x1b[31mλ\x1b[0m ");
@@ -34,7 +39,7 @@ pub fn run_repl() {
                     break;
                 }
 
-                let (_, tokens) = match tokenizer(&input) {
+                let tokens = match tokenizer(&input) {
                     Ok(tokens) => tokens,
                     Err(e) => {
                         println!("Error: {}", e);
@@ -42,7 +47,8 @@ pub fn run_repl() {
                     }
                 };
 
-                let mut parser = Parser { tokens, current: 0 };
+                let mut parser = Parser::with_first_line(tokens, line);
+                line += 1;
                 let ast = match parser.parse_tokens() {
                     Ok(ast) => ast,
                     Err(e) => {
@@ -50,14 +56,17 @@ pub fn run_repl() {
                         continue;
                     },
                 };
+                let ast = match expander.expand_program(ast) {
+                    Ok(ast) => ast,
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        continue;
+                    },
+                };
 
-                match evaluator.evaluate(ast) {
+                match evaluator.evaluate_program(ast) {
                     Ok(result) => match result {
-                        EvaluationResult::Value(val) | EvaluationResult::ReturnValue(val) => match val {
-                            ast::Literal::Int(i) => println!("{}", i),
-                            ast::Literal::String(s) => println!("{}", s),
-                            _ => println!("{:?}", val),
-                        },
+                        EvaluationResult::Value(val) | EvaluationResult::ReturnValue(val) => println!("{}", val),
                     },
                     Err(e) => println!("Error: {}", e),
                 }